@@ -7,19 +7,148 @@ pub fn app() -> App<'static, 'static> {
         .about("A simple commandline addressbook")
         .setting(AppSettings::SubcommandRequired)
         .subcommand(SubCommand::with_name("index")
-                    .about("Rewrite/create the index"))
+                    .about("Rewrite/create the index")
+                    .arg(Arg::with_name("full")
+                         .long("full")
+                         .help("Parse all contacts again, even those that didn't change since the last run."))
+                    .arg(Arg::with_name("check")
+                         .long("check")
+                         .conflicts_with("full")
+                         .help("Only report where the index and the contacts differ. Fails if they do.")))
+        .subcommand(SubCommand::with_name("watch")
+                    .about("Watch the contacts for changes and keep the index up to date."))
         .subcommand(SubCommand::with_name("mutt-query")
                     .about("Search for contact, output is usable for mutt's query_command.")
-                    .arg(Arg::with_name("query").index(1)))
+                    .arg(Arg::with_name("query").index(1))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
         .subcommand(SubCommand::with_name("file-query")
                     .about("Search for contact, return just the filename.")
-                    .arg(Arg::with_name("query").index(1)))
+                    .arg(Arg::with_name("query").index(1))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
         .subcommand(SubCommand::with_name("email-query")
                     .about("Search for contact, return \"name <email>\".")
-                    .arg(Arg::with_name("query").index(1)))
+                    .arg(Arg::with_name("query").index(1))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
+        .subcommand(SubCommand::with_name("tel-query")
+                    .about("Search for contact by phone number, return name and filename.")
+                    .arg(Arg::with_name("number").index(1).required(true))
+                    .arg(collection_arg()))
+        .subcommand(SubCommand::with_name("uid-query")
+                    .about("Search for contact by UID, return just the filename.")
+                    .arg(Arg::with_name("uid").index(1).required(true))
+                    .arg(collection_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
         .subcommand(SubCommand::with_name("add")
-                    .about("Take mail from stdin, add sender to contacts. Print filename."))
+                    .about("Take mail from stdin, add sender to contacts. Print filename.")
+                    .arg(target_collection_arg()))
+        .subcommand(SubCommand::with_name("new")
+                    .about("Create a contact with the given properties, or interactively if there are none. Print filename.")
+                    .arg(Arg::with_name("name")
+                         .long("name")
+                         .takes_value(true)
+                         .help("The full name."))
+                    .arg(Arg::with_name("email")
+                         .long("email")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("An email address, can be given several times."))
+                    .arg(Arg::with_name("tel")
+                         .long("tel")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .help("A phone number, can be given several times."))
+                    .arg(Arg::with_name("org")
+                         .long("org")
+                         .takes_value(true)
+                         .help("The organization."))
+                    .arg(Arg::with_name("note")
+                         .long("note")
+                         .takes_value(true)
+                         .help("A note."))
+                    .arg(target_collection_arg()))
+        .subcommand(SubCommand::with_name("used")
+                    .about("Record that the given email addresses were used, so they rank higher in queries.")
+                    .arg(Arg::with_name("address").index(1).multiple(true).required(true))
+                    .setting(AppSettings::AllowLeadingHyphen))
+        .subcommand(SubCommand::with_name("show")
+                    .about("Print contact (given by filepath, search-string or uid:<UID>) in a readable format.")
+                    .arg(Arg::with_name("file-or-query").index(1))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
+        .subcommand(SubCommand::with_name("duplicates")
+                    .about("Print groups of contacts that are probably the same person, with the reasons.")
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Print the groups as JSON."))
+                    .arg(collection_arg()))
+        .subcommand(SubCommand::with_name("merge")
+                    .about("Merge contacts (given by filepath, search-string or uid:<UID>) into the first one, \
+                            and move the others to the trash. Print filename.")
+                    .arg(Arg::with_name("file-or-query")
+                         .index(1)
+                         .multiple(true)
+                         .min_values(2)
+                         .required(true))
+                    .arg(Arg::with_name("prefer")
+                         .long("prefer")
+                         .takes_value(true)
+                         .possible_values(&["first", "last", "newest"])
+                         .help("Which contact's value to use if they differ, instead of asking."))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
+        .subcommand(SubCommand::with_name("rm")
+                    .about("Move contact (given by filepath, search-string or uid:<UID>) to the trash.")
+                    .arg(Arg::with_name("file-or-query").index(1).required(true))
+                    .arg(Arg::with_name("yes")
+                         .long("yes")
+                         .short("y")
+                         .help("Don't ask for confirmation."))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
+        .subcommand(SubCommand::with_name("trash")
+                    .about("Manage contacts removed with `mates rm`.")
+                    .setting(AppSettings::SubcommandRequired)
+                    .subcommand(SubCommand::with_name("list")
+                                .about("List contacts in the trash, with their filename and name."))
+                    .subcommand(SubCommand::with_name("restore")
+                                .about("Move contact (given by filename or path as listed) back from the trash. Print filename.")
+                                .arg(Arg::with_name("file").index(1).required(true))))
         .subcommand(SubCommand::with_name("edit")
-                    .about("Open contact (given by filepath or search-string) interactively.")
-                    .arg(Arg::with_name("file-or-query").index(1)))
+                    .about("Open contact (given by filepath, search-string or uid:<UID>) interactively.")
+                    .arg(Arg::with_name("file-or-query").index(1))
+                    .arg(collection_arg())
+                    .arg(match_arg())
+                    .setting(AppSettings::AllowLeadingHyphen))
+}
+
+fn collection_arg() -> Arg<'static, 'static> {
+    Arg::with_name("collection")
+        .long("collection")
+        .takes_value(true)
+        .help("Only search contacts in the given collection.")
+}
+
+fn target_collection_arg() -> Arg<'static, 'static> {
+    Arg::with_name("collection")
+        .long("collection")
+        .takes_value(true)
+        .help("The collection to add the contact to. Required if there are several.")
+}
+
+fn match_arg() -> Arg<'static, 'static> {
+    Arg::with_name("match")
+        .long("match")
+        .takes_value(true)
+        .help("How to match the query: substring, regex, fuzzy or command (runs MATES_GREP).")
 }
@@ -7,12 +7,20 @@ use std::io;
 use std::path;
 use std::process;
 
-use atomicwrites::{AtomicFile,AllowOverwrite};
+use clap;
+use vobject::Property;
 
 use utils;
-use utils::CustomPathExt;
 use app;
+use duplicates;
 use editor;
+use matcher;
+use matcher::Matcher;
+use merge;
+use lock::Lock;
+use show;
+use trash;
+use watch;
 
 
 #[inline]
@@ -29,61 +37,6 @@ fn get_envvar(key: &str) -> Option<String> {
     }
 }
 
-fn build_index(outfile: &path::Path, dir: &path::Path) -> MainResult<()> {
-    if !dir.is_dir() {
-        return Err(MainError::new("MATES_DIR must be a directory.").into());
-    };
-
-    let af = AtomicFile::new(&outfile, AllowOverwrite);
-    let mut errors = false;
-
-    try!(af.write::<(), io::Error, _>(|outf| {
-        for entry in try!(fs::read_dir(dir)) {
-            let entry = match entry {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Error while listing directory: {}", e);
-                    errors = true;
-                    continue;
-                }
-            };
-
-            let pathbuf = entry.path();
-
-            if pathbuf.str_extension().unwrap_or("") != "vcf" || !pathbuf.is_file() {
-                continue;
-            };
-
-            let contact = match utils::Contact::from_file(&pathbuf) {
-                Ok(x) => x,
-                Err(e) => {
-                    println!("Error while reading {}: {}", pathbuf.display(), e);
-                    errors = true;
-                    continue
-                }
-            };
-
-            match utils::index_item_from_contact(&contact) {
-                Ok(index_string) => {
-                    try!(outf.write_all(index_string.as_bytes()));
-                },
-                Err(e) => {
-                    println!("Error while indexing {}: {}", pathbuf.display(), e);
-                    errors = true;
-                    continue
-                }
-            };
-        };
-        Ok(())
-    }));
-
-    if errors {
-        Err(MainError::new("Several errors happened while generating the index.").into())
-    } else {
-        Ok(())
-    }
-}
-
 pub fn cli_main() {
     match cli_main_raw() {
         Err(e) => {
@@ -97,8 +50,6 @@ pub fn cli_main() {
 pub fn cli_main_raw() -> MainResult<()> {
     let matches = app::app().get_matches();
 
-    let command = matches.subcommand_name().unwrap();
-
     let config = match Configuration::new() {
         Ok(x) => x,
         Err(e) => {
@@ -106,12 +57,60 @@ pub fn cli_main_raw() -> MainResult<()> {
         }
     };
 
+    run(config, &matches)
+}
+
+/// Run the command given in `matches`, which were parsed with `app::app()`, with a configuration
+/// created by the caller, e.g. with additional matchers.
+pub fn run(mut config: Configuration, matches: &clap::ArgMatches) -> MainResult<()> {
+    let command = matches.subcommand_name().unwrap();
+
     let submatches = matches.subcommand_matches(command).expect("Internal error.");
+    config.collection = submatches.value_of("collection").map(|x| x.to_owned());
+    if let Some(x) = submatches.value_of("match") {
+        config.matcher = Some(x.to_owned());
+    };
 
     match command {
         "index" => {
-            println!("Rebuilding index file \"{}\"...", config.index_path.display());
-            try!(build_index(&config.index_path, &config.vdir_path));
+            if submatches.is_present("check") {
+                let problems = try!(utils::check_index(&config));
+                for problem in problems.iter() {
+                    println!("{}", problem);
+                };
+                let unfixable = problems.iter().filter(|x| !x.fixed_by_rebuild()).count();
+                if unfixable > 0 && unfixable == problems.len() {
+                    return Err(MainError::new(format!(
+                        "Found {} contacts that can't be indexed, they have to be fixed by hand.",
+                        unfixable
+                    )).into());
+                } else if unfixable > 0 {
+                    return Err(MainError::new(format!(
+                        "Found {} problems with the index. {} contacts can't be indexed and have to be \
+                         fixed by hand, `mates index --full` fixes the rest.",
+                        problems.len(),
+                        unfixable
+                    )).into());
+                } else if !problems.is_empty() {
+                    return Err(MainError::new(format!(
+                        "Found {} problems with the index, run `mates index --full` to fix them.",
+                        problems.len()
+                    )).into());
+                };
+                return Ok(());
+            };
+
+            let full = submatches.is_present("full");
+            if full {
+                println!("Rebuilding index file \"{}\"...", config.index_path.display());
+            } else {
+                println!("Updating index file \"{}\"...", config.index_path.display());
+            }
+            try!(utils::build_index(&config, full));
+        },
+        "watch" => {
+            println!("Watching for changes, the index file is \"{}\".", config.index_path.display());
+            try!(watch::watch(&config));
         },
         "mutt-query" => {
             let query = submatches.value_of("query").unwrap_or("");
@@ -125,23 +124,106 @@ pub fn cli_main_raw() -> MainResult<()> {
             let query = submatches.value_of("query").unwrap_or("");
             try!(email_query(&config, &query[..]));
         },
+        "tel-query" => {
+            let number = submatches.value_of("number").unwrap_or("");
+            try!(tel_query(&config, &number[..]));
+        },
+        "uid-query" => {
+            let uid = submatches.value_of("uid").unwrap_or("");
+            let paths = try!(utils::uid_query(&config, uid));
+            if paths.is_empty() {
+                return Err(MainError::new("No such contact.").into());
+            };
+            for path in paths.iter() {
+                println!("{}", path.display());
+            };
+        },
         "add" => {
             let stdin = io::stdin();
             let mut email = String::new();
             try!(stdin.lock().read_to_string(&mut email));
-            let contact = try!(utils::add_contact_from_email(
-                &config.vdir_path,
-                &email[..]
+            let collection = try!(utils::find_collection(
+                &config,
+                config.collection.as_ref().map(|x| &x[..])
             ));
+            let contact = {
+                let _lock = try!(Lock::acquire(&config));
+                try!(utils::add_contact_from_email(
+                    &collection.path,
+                    &email[..]
+                ))
+            };
             println!("{}", contact.path.display());
+            try!(utils::update_index_for_file(&config, &contact.path));
 
-            let mut index_fp = try!(fs::OpenOptions::new()
-                                    .append(true)
-                                    .write(true)
-                                    .open(&config.index_path));
-
-            let index_entry = try!(utils::index_item_from_contact(&contact));
-            try!(index_fp.write_all(index_entry.as_bytes()));
+            let emails: Vec<String> = contact.component.get_all("EMAIL").iter()
+                .map(|x| x.value_as_string())
+                .collect();
+            let emails: Vec<&str> = emails.iter().map(|x| &x[..]).collect();
+            try!(utils::record_usage(&config, &emails[..]));
+        },
+        "new" => {
+            try!(new_contact(&config, submatches));
+        },
+        "used" => {
+            if !config.track_usage {
+                return Err(MainError::new("Usage tracking is disabled, set MATES_TRACK_USAGE=1.").into());
+            };
+            let addresses: Vec<String> = submatches.values_of("address")
+                .map(|x| x.map(|x| x.to_owned()).collect())
+                .unwrap_or_else(Vec::new);
+            // E.g. sendmail's options, when used as in the README.
+            let emails: Vec<&str> = addresses.iter()
+                .filter_map(|x| utils::parse_from_header(x).1)
+                .filter(|x| x.contains('@'))
+                .collect();
+            try!(utils::record_usage(&config, &emails[..]));
+        },
+        "show" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            try!(show_contact(&config, &query[..]));
+        },
+        "duplicates" => {
+            let groups = try!(duplicates::find_duplicates(&config));
+            if submatches.is_present("json") {
+                println!("{}", duplicates::groups_to_json(&groups[..]));
+            } else {
+                print_duplicates(&groups);
+            };
+        },
+        "merge" => {
+            let queries: Vec<&str> = submatches.values_of("file-or-query")
+                .map(|x| x.collect())
+                .unwrap_or_else(Vec::new);
+            let prefer = match submatches.value_of("prefer") {
+                Some("first") => Some(merge::Prefer::First),
+                Some("last") => Some(merge::Prefer::Last),
+                Some("newest") => Some(merge::Prefer::Newest),
+                _ => None
+            };
+            try!(merge_contacts(&config, &queries[..], prefer));
+        },
+        "rm" => {
+            let query = submatches.value_of("file-or-query").unwrap_or("");
+            try!(remove_contact(&config, &query[..], submatches.is_present("yes")));
+        },
+        "trash" => {
+            match submatches.subcommand() {
+                ("list", _) => {
+                    for contact in try!(trash::list_trash(&config)) {
+                        let name = utils::Contact::from_file(&contact.path).ok()
+                            .and_then(|x| utils::contact_display_name(&x.component))
+                            .unwrap_or_else(String::new);
+                        println!("{}\t{}", contact.path.display(), name);
+                    };
+                },
+                ("restore", Some(restore_matches)) => {
+                    let file = restore_matches.value_of("file").unwrap_or("");
+                    let path = try!(trash::restore_from_trash(&config, file));
+                    println!("{}", path.display());
+                },
+                _ => return Err(MainError::new("Invalid command.").into())
+            };
         },
         "edit" => {
             let query = submatches.value_of("file-or-query").unwrap_or("");
@@ -154,9 +236,13 @@ pub fn cli_main_raw() -> MainResult<()> {
     Ok(())
 }
 
-fn edit_contact(config: &Configuration, query: &str) -> MainResult<()> {
-    let results = if get_pwd().join(query).is_file() {
-        vec![path::PathBuf::from(query)]
+/// Find the file of the contact given by filepath, `uid:<UID>` or search string, which has to
+/// match exactly one contact.
+fn find_contact_file(config: &Configuration, query: &str) -> MainResult<path::PathBuf> {
+    let mut results = if get_pwd().join(query).is_file() {
+        vec![get_pwd().join(query)]
+    } else if query.starts_with("uid:") {
+        try!(utils::uid_query(config, &query[4..]))
     } else {
         try!(utils::file_query(config, query)).into_iter().collect()
     };
@@ -166,9 +252,199 @@ fn edit_contact(config: &Configuration, query: &str) -> MainResult<()> {
     } else if results.len() > 1 {
         return Err(MainError::new("Ambiguous query.").into());
     }
+    Ok(results.remove(0))
+}
+
+fn new_contact(config: &Configuration, submatches: &clap::ArgMatches) -> MainResult<()> {
+    let collection = try!(utils::find_collection(
+        config,
+        config.collection.as_ref().map(|x| &x[..])
+    ));
+
+    let values = |name| -> Vec<&str> {
+        submatches.values_of(name).map(|x| x.collect()).unwrap_or_else(Vec::new)
+    };
+    let name = submatches.value_of("name");
+    let emails = values("email");
+    let tels = values("tel");
+    let org = submatches.value_of("org");
+    let note = submatches.value_of("note");
+    let interactive = name.is_none() && emails.is_empty() && tels.is_empty() && org.is_none() && note.is_none();
+
+    let mut contact = utils::Contact::generate(name, emails.first().map(|x| *x), &collection.path);
+    for email in emails.iter().skip(1) {
+        contact.component.push(Property::new("EMAIL", email));
+    };
+    for tel in tels.iter() {
+        contact.component.push(Property::new("TEL", tel));
+    };
+    if let Some(x) = org {
+        contact.component.push(Property::new("ORG", x));
+    };
+    if let Some(x) = note {
+        contact.component.push(Property::new("NOTE", x));
+    };
+
+    if !interactive && name.is_none() {
+        // FN is required, so it is set to what the contact would be displayed as.
+        match utils::contact_display_name(&contact.component) {
+            Some(x) => contact.component.push(Property::new("FN", &x[..])),
+            None => return Err(MainError::new("The contact needs a name, email address or organization.").into())
+        };
+    };
 
-    let fpath = &results[0];
-    editor::cli_main(fpath);
+    {
+        let _lock = try!(Lock::acquire(config));
+        try!(contact.write_create());
+    }
+
+    if interactive {
+        // An aborted or broken edit must not leave a half-made contact in the vdir.
+        let edited = editor::cli_main(config, &contact.path)
+            .and_then(|_| utils::Contact::from_file(&contact.path))
+            .map_err(|e| e.to_string())
+            .and_then(|x| match utils::contact_display_name(&x.component) {
+                Some(_) => Ok(()),
+                None => Err("the contact has no name".to_owned())
+            });
+        if let Err(reason) = edited {
+            {
+                let _lock = try!(Lock::acquire(config));
+                if let Err(e) = fs::remove_file(&contact.path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    };
+                };
+            }
+            return Err(MainError::new(format!("Contact not created: {}", reason)).into());
+        };
+    };
+
+    println!("{}", contact.path.display());
+    try!(utils::update_index_for_file(config, &contact.path));
+    Ok(())
+}
+
+fn show_contact(config: &Configuration, query: &str) -> MainResult<()> {
+    let fpath = try!(find_contact_file(config, query));
+    let contact = try!(utils::Contact::from_file(&fpath));
+    print!("{}", show::format_contact(&contact.component));
+    Ok(())
+}
+
+fn print_duplicates(groups: &[duplicates::DuplicateGroup]) {
+    for (i, group) in groups.iter().enumerate() {
+        if i > 0 {
+            println!("");
+        };
+        for contact in group.contacts.iter() {
+            println!("{}\t{}", contact.path.display(), contact.name);
+        };
+        for evidence in group.evidence.iter() {
+            // Only say which contacts the evidence is about if it isn't all of them.
+            let files = if evidence.paths.len() < group.contacts.len() {
+                let names: Vec<String> = evidence.paths.iter()
+                    .map(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default())
+                    .collect();
+                format!(" ({})", names.join(", "))
+            } else {
+                String::new()
+            };
+            println!("  {}: {}{}", evidence.kind.description(), evidence.values.join(" / "), files);
+        };
+    };
+}
+
+fn merge_contacts(config: &Configuration, queries: &[&str], prefer: Option<merge::Prefer>) -> MainResult<()> {
+    let mut contacts: Vec<utils::Contact> = vec![];
+    for query in queries.iter() {
+        let fpath = try!(find_contact_file(config, query));
+        if contacts.iter().any(|x| x.path == fpath) {
+            return Err(MainError::new(format!("{} was given twice.", fpath.display())).into());
+        };
+        contacts.push(try!(utils::Contact::from_file(&fpath)));
+    };
+
+    let order = match prefer {
+        Some(x) => Some(try!(merge::preference_order(&contacts, x))),
+        None => None
+    };
+    let country_prefix = config.country_prefix.as_ref().map(|x| &x[..]);
+    let component = try!(merge::merge_contacts(&contacts, country_prefix, |name, options| {
+        match order {
+            // The option of the most preferred contact that has the property.
+            Some(ref order) => Ok(order.iter()
+                .filter_map(|&i| options.iter().position(|x| x.paths.contains(&contacts[i].path)))
+                .next()
+                .unwrap_or(0)),
+            None => choose_conflict_option(name, options)
+        }
+    }));
+
+    let merged = utils::Contact { component: component, path: contacts[0].path.clone() };
+    {
+        let _lock = try!(Lock::acquire(config));
+        try!(merged.write_replace());
+    }
+    try!(utils::update_index_for_file(config, &merged.path));
+    for contact in contacts[1..].iter() {
+        try!(trash::move_to_trash(config, &contact.path));
+    };
+    println!("{}", merged.path.display());
+    Ok(())
+}
+
+/// Ask which of the differing values of a property to use.
+fn choose_conflict_option(name: &str, options: &[merge::ConflictOption]) -> io::Result<usize> {
+    println!("{} differs:", name);
+    for (i, option) in options.iter().enumerate() {
+        let values: Vec<String> = option.props.iter()
+            .flat_map(|x| utils::property_values(x).into_iter())
+            .collect();
+        let paths: Vec<String> = option.paths.iter().map(|x| x.display().to_string()).collect();
+        println!("  {}) {} ({})", i + 1, values.join(" / "), paths.join(", "));
+    };
+
+    loop {
+        print!("Choose [1-{}]: ", options.len());
+        try!(io::stdout().flush());
+        let mut answer = String::new();
+        if try!(io::stdin().read_line(&mut answer)) == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Aborted."));
+        };
+        match answer.trim().parse::<usize>() {
+            Ok(x) if x >= 1 && x <= options.len() => return Ok(x - 1),
+            _ => ()
+        };
+    }
+}
+
+fn remove_contact(config: &Configuration, query: &str, confirmed: bool) -> MainResult<()> {
+    let fpath = try!(find_contact_file(config, query));
+
+    if !confirmed {
+        let name = utils::Contact::from_file(&fpath).ok()
+            .and_then(|x| utils::contact_display_name(&x.component))
+            .unwrap_or_else(String::new);
+        print!("Move {} ({}) to the trash? [y/N] ", name, fpath.display());
+        try!(io::stdout().flush());
+
+        let mut answer = String::new();
+        try!(io::stdin().read_line(&mut answer));
+        match &answer.trim().to_lowercase()[..] {
+            "y" | "yes" => (),
+            _ => return Err(MainError::new("Aborted.").into())
+        };
+    };
+
+    let trash_path = try!(trash::move_to_trash(config, &fpath));
+    println!("{}", trash_path.display());
+    Ok(())
+}
+
+fn edit_contact(config: &Configuration, query: &str) -> MainResult<()> {
+    let fpath = &try!(find_contact_file(config, query));
+    try!(editor::cli_main(config, fpath));
 
     let fcontent = {
         let mut fcontent = String::new();
@@ -178,20 +454,33 @@ fn edit_contact(config: &Configuration, query: &str) -> MainResult<()> {
     };
 
     if (&fcontent[..]).trim().len() == 0 {
-        try!(fs::remove_file(fpath));
+        {
+            let _lock = try!(Lock::acquire(config));
+            try!(fs::remove_file(fpath));
+        }
+        try!(utils::update_index_for_file(&config, fpath));
         return Err(MainError::new("Contact emptied, file removed.").into());
     };
 
+    try!(utils::update_index_for_file(&config, fpath));
     Ok(())
 }
 
 fn mutt_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
     println!("");  // For some reason mutt requires an empty line
     // We need to ignore errors here, otherwise mutt's UI will glitch
-    if let Ok(items) = utils::index_query(config, query) {
+    if let Ok(items) = utils::ranked_index_query(config, query, matcher::FUZZY) {
         for item in items {
             if item.email.len() > 0 && item.name.len() > 0 {
-                println!("{}\t{}", item.email, item.name);
+                // The third column is shown by mutt as additional information.
+                let info: Vec<&str> = item.fields.iter()
+                    .flat_map(|&(_, ref values)| values.iter().map(|x| &x[..]))
+                    .collect();
+                if info.is_empty() {
+                    println!("{}\t{}", item.email, item.name);
+                } else {
+                    println!("{}\t{}\t{}", item.email, item.name, info.join(", "));
+                }
             };
         };
     };
@@ -206,7 +495,7 @@ fn file_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
 }
 
 fn email_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
-    for item in try!(utils::index_query(config, query)) {
+    for item in try!(utils::ranked_index_query(config, query, matcher::SUBSTRING)) {
         if item.name.len() > 0 && item.email.len() > 0 {
             println!("{} <{}>", item.name, item.email);
         };
@@ -214,32 +503,160 @@ fn email_query<'a>(config: &Configuration, query: &str) -> MainResult<()> {
     Ok(())
 }
 
+fn tel_query(config: &Configuration, number: &str) -> MainResult<()> {
+    let items = try!(utils::tel_query(config, number));
+    if items.is_empty() {
+        return Err(MainError::new("No such contact.").into());
+    };
+    for item in items {
+        if let Some(ref path) = item.filepath {
+            println!("{}\t{}", item.name, path.display());
+        };
+    };
+    Ok(())
+}
+
+/// What to do when a query finds that contacts changed after the index was last written.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum StaleIndexAction {
+    Warn,
+    Rebuild,
+    Ignore
+}
+
+/// Where the index is stored.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum IndexBackendKind {
+    Text,
+    Sqlite
+}
+
 pub struct Configuration {
     pub index_path: path::PathBuf,
-    pub vdir_path: path::PathBuf,
-    pub grep_cmd: String
+    pub index_backend: IndexBackendKind,
+    pub vdir_paths: Vec<path::PathBuf>,
+    pub grep_cmd: Option<String>,
+    pub stale_index: StaleIndexAction,
+    pub index_fields: Vec<String>,
+    pub track_usage: bool,
+    pub usage_path: path::PathBuf,
+    /// Taken while writing to the index, the usage file or the contacts, see the `lock` module.
+    pub lock_path: path::PathBuf,
+    /// Where `mates rm` moves contacts to, see the `trash` module.
+    pub trash_path: path::PathBuf,
+    /// The country calling code of phone numbers without one, e.g. `+49`.
+    pub country_prefix: Option<String>,
+    /// The name of the matcher given with `--match` or `MATES_MATCH`, see `matcher`.
+    pub matcher: Option<String>,
+    /// All available matchers by name, see `register_matcher`.
+    pub matchers: Vec<(String, Box<Matcher>)>,
+    /// The collection given with `--collection`. Queries only return contacts from it, and new
+    /// contacts are created in it.
+    pub collection: Option<String>
 }
 
 impl Configuration {
     pub fn new() -> Result<Configuration, String> {
+        let index_path = match get_envvar("MATES_INDEX") {
+            Some(x) => path::PathBuf::from(&x),
+            None => match get_envvar("HOME") {
+                Some(home) => get_pwd().join(&home).join(".mates_index"),
+                None => return Err("Unable to determine user's home directory.".to_owned())
+            }
+        };
+
         Ok(Configuration {
-            index_path: match get_envvar("MATES_INDEX") {
-                Some(x) => path::PathBuf::from(&x),
-                None => match get_envvar("HOME") {
-                    Some(home) => get_pwd().join(&home).join(".mates_index"),
-                    None => return Err("Unable to determine user's home directory.".to_owned())
+            usage_path: {
+                let mut x = index_path.clone().into_os_string();
+                x.push(".usage");
+                path::PathBuf::from(x)
+            },
+            lock_path: {
+                let mut x = index_path.clone().into_os_string();
+                x.push(".lock");
+                path::PathBuf::from(x)
+            },
+            trash_path: {
+                let mut x = index_path.clone().into_os_string();
+                x.push(".trash");
+                path::PathBuf::from(x)
+            },
+            index_path: index_path,
+            index_backend: match get_envvar("MATES_INDEX_BACKEND") {
+                None => IndexBackendKind::Text,
+                Some(x) => match &x[..] {
+                    "text" => IndexBackendKind::Text,
+                    "sqlite" => IndexBackendKind::Sqlite,
+                    _ => return Err(format!("Invalid value for MATES_INDEX_BACKEND: {}", x))
                 }
             },
-            vdir_path: match get_envvar("MATES_DIR") {
-                Some(x) => path::PathBuf::from(&x),
+            vdir_paths: match get_envvar("MATES_DIR") {
+                Some(x) => env::split_paths(&x).filter(|x| !x.as_os_str().is_empty()).collect(),
                 None => return Err("MATES_DIR must be set to your vdir path (directory of vcf-files).".to_owned())
             },
-            grep_cmd: match get_envvar("MATES_GREP") {
-                Some(x) => x,
-                None => "grep -i".to_owned()
-            }
+            grep_cmd: get_envvar("MATES_GREP"),
+            stale_index: match get_envvar("MATES_STALE_INDEX") {
+                None => StaleIndexAction::Warn,
+                Some(x) => match &x[..] {
+                    "warn" => StaleIndexAction::Warn,
+                    "rebuild" => StaleIndexAction::Rebuild,
+                    "ignore" => StaleIndexAction::Ignore,
+                    _ => return Err(format!("Invalid value for MATES_STALE_INDEX: {}", x))
+                }
+            },
+            index_fields: match get_envvar("MATES_INDEX_FIELDS") {
+                Some(x) => x.split(',')
+                    .map(|x| x.trim().to_uppercase())
+                    .filter(|x| !x.is_empty())
+                    .collect(),
+                None => vec![]
+            },
+            track_usage: match get_envvar("MATES_TRACK_USAGE") {
+                Some(x) => x == "1" || x == "true",
+                None => false
+            },
+            country_prefix: match get_envvar("MATES_COUNTRY_PREFIX") {
+                Some(x) => {
+                    let prefix = utils::strip_tel(&x[..]);
+                    let digits = prefix.trim_left_matches('+');
+                    if digits.is_empty() || digits.starts_with('0') {
+                        return Err(format!("Invalid value for MATES_COUNTRY_PREFIX: {}", x));
+                    };
+                    Some(format!("+{}", digits))
+                },
+                None => None
+            },
+            matcher: get_envvar("MATES_MATCH"),
+            matchers: matcher::builtin_matchers(),
+            collection: None
         })
     }
+
+    /// Make a matcher available under the given name, replacing any existing one with that name.
+    pub fn register_matcher<M: Matcher + 'static>(&mut self, name: &str, matcher: M) {
+        self.matchers.retain(|&(ref x, _)| x != name);
+        self.matchers.push((name.to_owned(), Box::new(matcher)));
+    }
+
+    /// The matcher chosen with `--match` or `MATES_MATCH`. Otherwise the command matcher if
+    /// `MATES_GREP` is set, and the one named `default` if not.
+    pub fn matcher(&self, default: &str) -> io::Result<&Matcher> {
+        let name = match (&self.matcher, &self.grep_cmd) {
+            (&Some(ref x), _) => &x[..],
+            (&None, &Some(_)) => matcher::COMMAND,
+            (&None, &None) => default
+        };
+        match self.matchers.iter().find(|&&(ref x, _)| x == name) {
+            Some(&(_, ref x)) => Ok(&**x),
+            None => {
+                let names: Vec<&str> = self.matchers.iter().map(|&(ref x, _)| &x[..]).collect();
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("No such matcher: {}, choose one of: {}", name, names.join(", "))
+                ))
+            }
+        }
+    }
 }
 
 
@@ -0,0 +1,568 @@
+//! Finding contacts that are probably the same person, e.g. because `mates add` was used on an
+//! address that was already known. Contacts are grouped if they share an email address or phone
+//! number, or if their names are the same apart from case, diacritics, word order and a typo. The
+//! name is FN, or whatever `contact_display_name` derives it from; N is compared as well if it is
+//! in `MATES_INDEX_FIELDS`. Short names like "Anna" are shared by too many people to group
+//! contacts on their own, so they only count for contacts that also share an email address or
+//! phone number.
+
+use std::cmp;
+use std::collections::{BTreeMap,HashMap};
+use std::io;
+use std::path;
+
+use cli::Configuration;
+use query;
+use usage;
+use utils;
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum EvidenceKind {
+    Email,
+    Tel,
+    Name
+}
+
+impl EvidenceKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EvidenceKind::Email => "email",
+            EvidenceKind::Tel => "tel",
+            EvidenceKind::Name => "name"
+        }
+    }
+
+    /// How the evidence is introduced in the plain text output.
+    pub fn description(&self) -> &'static str {
+        match *self {
+            EvidenceKind::Email => "same email address",
+            EvidenceKind::Tel => "same phone number",
+            EvidenceKind::Name => "similar names"
+        }
+    }
+}
+
+/// Why some contacts of a group are considered duplicates.
+pub struct Evidence {
+    pub kind: EvidenceKind,
+    /// The shared email address or phone number, or all the similar names.
+    pub values: Vec<String>,
+    /// The contacts sharing it, sorted.
+    pub paths: Vec<path::PathBuf>
+}
+
+pub struct DuplicateContact {
+    pub path: path::PathBuf,
+    pub name: String,
+    pub uid: String,
+    pub collection: String
+}
+
+/// Contacts that are probably the same person. Every contact is connected to every other by a
+/// chain of evidence.
+pub struct DuplicateGroup {
+    /// Sorted by path.
+    pub contacts: Vec<DuplicateContact>,
+    pub evidence: Vec<Evidence>
+}
+
+/// `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut rv = String::with_capacity(value.len() + 2);
+    rv.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            c if (c as u32) < 0x20 => rv.push_str(&format!("\\u{:04x}", c as u32)),
+            c => rv.push(c)
+        };
+    };
+    rv.push('"');
+    rv
+}
+
+fn json_string_array<S: AsRef<str>>(values: &[S]) -> String {
+    let values: Vec<String> = values.iter().map(|x| json_string(x.as_ref())).collect();
+    format!("[{}]", values.join(", "))
+}
+
+/// The groups as a JSON array. Every group is an object with `contacts` and `evidence`, which have
+/// one object per line.
+pub fn groups_to_json(groups: &[DuplicateGroup]) -> String {
+    let groups: Vec<String> = groups.iter()
+        .map(|group| {
+            let contacts: Vec<String> = group.contacts.iter()
+                .map(|contact| format!(
+                    "      {{\"path\": {}, \"name\": {}, \"uid\": {}, \"collection\": {}}}",
+                    json_string(&contact.path.display().to_string()[..]),
+                    json_string(&contact.name[..]),
+                    json_string(&contact.uid[..]),
+                    json_string(&contact.collection[..])
+                ))
+                .collect();
+            let evidence: Vec<String> = group.evidence.iter()
+                .map(|evidence| {
+                    let paths: Vec<String> = evidence.paths.iter().map(|x| x.display().to_string()).collect();
+                    format!(
+                        "      {{\"kind\": {}, \"values\": {}, \"paths\": {}}}",
+                        json_string(evidence.kind.as_str()),
+                        json_string_array(&evidence.values[..]),
+                        json_string_array(&paths[..])
+                    )
+                })
+                .collect();
+            format!(
+                "  {{\n    \"contacts\": [\n{}\n    ],\n    \"evidence\": [\n{}\n    ]\n  }}",
+                contacts.join(",\n"),
+                evidence.join(",\n")
+            )
+        })
+        .collect();
+    if groups.is_empty() {
+        "[]".to_owned()
+    } else {
+        format!("[\n{}\n]", groups.join(",\n"))
+    }
+}
+
+/// A contact as found in the index, with everything it can be matched by.
+struct Candidate {
+    contact: DuplicateContact,
+    emails: Vec<String>,
+    tels: Vec<String>,
+    names: Vec<String>
+}
+
+/// Disjoint sets of candidates, by index.
+struct Groups {
+    parents: Vec<usize>
+}
+
+impl Groups {
+    fn new(len: usize) -> Groups {
+        Groups { parents: (0..len).collect() }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        };
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents[a] = b;
+    }
+}
+
+/// A name in a form that is the same for "Müller, Anna" and "anna muller".
+fn name_key(name: &str) -> String {
+    let folded = query::fold(name);
+    let mut words: Vec<&str> = query::words(&folded[..]).collect();
+    words.sort();
+    words.join(" ")
+}
+
+/// Name keys shorter than this need a shared email address or phone number to group contacts.
+const SHORT_NAME_LEN: usize = 8;
+
+/// How many typos a name key of `len` characters may contain: one from four characters on, and
+/// one per eight characters after that.
+fn max_typos(len: usize) -> usize {
+    if len < 4 { 0 } else { cmp::max(1, len / 8) }
+}
+
+/// Whether two name keys differ by no more typos than the longer one may contain.
+fn similar_names(a: &[char], b: &[char], buffer: &mut Vec<usize>) -> bool {
+    let max_typos = max_typos(cmp::max(a.len(), b.len()));
+    max_typos > 0 && query::within_edit_distance(a, b, max_typos, buffer)
+}
+
+/// The keys in `bucket` to compare the `i`th with. Keys are compared with the shorter keys and the
+/// keys of the same length after them, so every pair of keys is only compared once.
+fn keys_to_compare(bucket: Option<&Vec<usize>>, i: usize, same_length: bool) -> &[usize] {
+    match bucket {
+        Some(x) if same_length => &x[x.binary_search(&i).map(|x| x + 1).unwrap_or_else(|x| x)..],
+        Some(x) => &x[..],
+        None => &[]
+    }
+}
+
+fn join_members(groups: &mut Groups, members: &[usize]) {
+    for &member in members[1..].iter() {
+        groups.join(members[0], member);
+    };
+}
+
+/// The contacts of the index (of the selected collection), one per file, in index order.
+fn read_candidates(config: &Configuration) -> io::Result<Vec<Candidate>> {
+    try!(utils::check_index_freshness(config));
+    let (_, items) = try!(try!(utils::open_index(config)).load());
+    let country_prefix = config.country_prefix.as_ref().map(|x| &x[..]);
+
+    let mut rv: Vec<Candidate> = vec![];
+    let mut positions: HashMap<path::PathBuf, usize> = HashMap::new();
+
+    for item in items {
+        if !utils::in_selected_collection(config, &item) {
+            continue;
+        };
+        let filepath = match item.filepath {
+            Some(ref x) => x.clone(),
+            None => continue
+        };
+
+        let position = match positions.get(&filepath) {
+            Some(x) => *x,
+            None => {
+                let mut names = vec![item.name.clone()];
+                for &(ref field, ref values) in item.fields.iter() {
+                    if field == "N" {
+                        names.extend(values.iter().cloned());
+                    };
+                };
+                rv.push(Candidate {
+                    contact: DuplicateContact {
+                        path: filepath.clone(),
+                        name: item.name.clone(),
+                        uid: item.uid.clone(),
+                        collection: item.collection.clone()
+                    },
+                    emails: vec![],
+                    tels: item.tels.iter()
+                        .map(|x| utils::normalize_tel(&x[..], country_prefix))
+                        .filter(|x| !x.is_empty())
+                        .collect(),
+                    names: names
+                });
+                positions.insert(filepath, rv.len() - 1);
+                rv.len() - 1
+            }
+        };
+
+        let email = usage::normalize_email(&item.email[..]);
+        if !email.is_empty() && !rv[position].emails.contains(&email) {
+            rv[position].emails.push(email);
+        };
+    };
+    Ok(rv)
+}
+
+/// Every value of the candidates, with the candidates that have it, in the order they were first
+/// seen.
+fn values_with_candidates<F>(candidates: &[Candidate], values: F) -> Vec<(String, Vec<usize>)>
+    where F: Fn(&Candidate) -> Vec<String>
+{
+    let mut rv: Vec<(String, Vec<usize>)> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (i, candidate) in candidates.iter().enumerate() {
+        for value in values(candidate) {
+            let position = *positions.entry(value.clone()).or_insert_with(|| {
+                rv.push((value, vec![]));
+                rv.len() - 1
+            });
+            if !rv[position].1.contains(&i) {
+                rv[position].1.push(i);
+            };
+        };
+    };
+    rv
+}
+
+/// Like `values_with_candidates`, but only the values shared by more than one candidate.
+fn shared_values<F>(candidates: &[Candidate], values: F) -> Vec<(String, Vec<usize>)>
+    where F: Fn(&Candidate) -> Vec<String>
+{
+    values_with_candidates(candidates, values).into_iter()
+        .filter(|&(_, ref x)| x.len() > 1)
+        .collect()
+}
+
+/// Find groups of contacts that are probably the same person, sorted by the path of their first
+/// contact.
+pub fn find_duplicates(config: &Configuration) -> io::Result<Vec<DuplicateGroup>> {
+    let candidates = try!(read_candidates(config));
+    Ok(group_candidates(candidates))
+}
+
+fn group_candidates(candidates: Vec<Candidate>) -> Vec<DuplicateGroup> {
+    let mut groups = Groups::new(candidates.len());
+    // Evidence, with the candidates it applies to.
+    let mut evidence: Vec<(EvidenceKind, Vec<String>, Vec<usize>)> = vec![];
+
+    for (email, members) in shared_values(&candidates, |x| x.emails.clone()) {
+        evidence.push((EvidenceKind::Email, vec![email], members));
+    };
+    for (tel, members) in shared_values(&candidates, |x| x.tels.clone()) {
+        evidence.push((EvidenceKind::Tel, vec![tel], members));
+    };
+    for &(_, _, ref members) in evidence.iter() {
+        join_members(&mut groups, members);
+    };
+
+    // Candidates with the same name key are grouped, then keys that only differ by a typo.
+    let keys = values_with_candidates(&candidates, |x| {
+        x.names.iter().map(|x| name_key(&x[..])).filter(|x| !x.is_empty()).collect()
+    });
+    // Comparing every pair of keys is too slow for large address books. Only keys with a similar
+    // length and the same first or last character are compared, which still finds all keys that
+    // differ by one typo. Every pair is compared by the longer key.
+    let key_chars: Vec<Vec<char>> = keys.iter().map(|x| x.0.chars().collect()).collect();
+    let mut by_first: HashMap<(char, usize), Vec<usize>> = HashMap::new();
+    let mut by_last: HashMap<(char, usize), Vec<usize>> = HashMap::new();
+    for (i, chars) in key_chars.iter().enumerate() {
+        by_first.entry((chars[0], chars.len())).or_insert_with(Vec::new).push(i);
+        by_last.entry((chars[chars.len() - 1], chars.len())).or_insert_with(Vec::new).push(i);
+    };
+    let mut key_groups = Groups::new(keys.len());
+    let mut buffer = vec![];
+    for (i, a) in key_chars.iter().enumerate() {
+        let (first, last) = (a[0], a[a.len() - 1]);
+        let max_typos = max_typos(a.len());
+        if max_typos == 0 {
+            continue;
+        };
+        for len in a.len() - max_typos..a.len() + 1 {
+            let same_length = len == a.len();
+            let same_first = keys_to_compare(by_first.get(&(first, len)), i, same_length).iter();
+            let same_last = keys_to_compare(by_last.get(&(last, len)), i, same_length).iter()
+                .filter(|&&j| key_chars[j][0] != first);
+            for &j in same_first.chain(same_last) {
+                if similar_names(&a[..], &key_chars[j][..], &mut buffer) {
+                    key_groups.join(i, j);
+                };
+            };
+        };
+    };
+    let mut key_group_of: HashMap<String, usize> = HashMap::new();
+    let mut members_by_key_group: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    let mut short_key_groups: HashMap<usize, bool> = HashMap::new();
+    for (i, &(ref key, ref members)) in keys.iter().enumerate() {
+        let root = key_groups.find(i);
+        key_group_of.insert(key.clone(), root);
+        *short_key_groups.entry(root).or_insert(true) &= key_chars[i].len() < SHORT_NAME_LEN;
+        let group_members = members_by_key_group.entry(root).or_insert_with(Vec::new);
+        for &member in members.iter() {
+            if !group_members.contains(&member) {
+                group_members.push(member);
+            };
+        };
+    };
+    let mut name_evidence: Vec<(EvidenceKind, Vec<String>, Vec<usize>)> = vec![];
+    for (root, mut members) in members_by_key_group {
+        members.sort();
+        // Contacts with short names are only similar to those they already share an email
+        // address or phone number with.
+        let member_sets = if short_key_groups[&root] {
+            let mut by_group: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+            for &member in members.iter() {
+                by_group.entry(groups.find(member)).or_insert_with(Vec::new).push(member);
+            };
+            by_group.into_iter().map(|(_, x)| x).collect()
+        } else {
+            vec![members]
+        };
+        for members in member_sets {
+            if members.len() < 2 {
+                continue;
+            };
+            // Only the names that are similar, not other names of the same contacts.
+            let mut names: Vec<String> = vec![];
+            for &member in members.iter() {
+                for name in candidates[member].names.iter() {
+                    let name = name.trim().to_owned();
+                    if key_group_of.get(&name_key(&name[..])) == Some(&root) && !names.contains(&name) {
+                        names.push(name);
+                    };
+                };
+            };
+            name_evidence.push((EvidenceKind::Name, names, members));
+        };
+    };
+    for &(_, _, ref members) in name_evidence.iter() {
+        join_members(&mut groups, members);
+    };
+    evidence.extend(name_evidence);
+
+    let mut members_by_group: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..candidates.len() {
+        let root = groups.find(i);
+        members_by_group.entry(root).or_insert_with(Vec::new).push(i);
+    };
+    let mut evidence_by_group: HashMap<usize, Vec<Evidence>> = HashMap::new();
+    for (kind, values, members) in evidence {
+        let root = groups.find(members[0]);
+        let mut paths: Vec<path::PathBuf> = members.iter()
+            .map(|&i| candidates[i].contact.path.clone())
+            .collect();
+        paths.sort();
+        evidence_by_group.entry(root).or_insert_with(Vec::new)
+            .push(Evidence { kind: kind, values: values, paths: paths });
+    };
+
+    let mut candidates: Vec<Option<Candidate>> = candidates.into_iter().map(Some).collect();
+    let mut rv: Vec<DuplicateGroup> = vec![];
+    for (root, members) in members_by_group {
+        if members.len() < 2 {
+            continue;
+        };
+        let mut contacts: Vec<DuplicateContact> = members.iter()
+            .filter_map(|&i| candidates[i].take())
+            .map(|x| x.contact)
+            .collect();
+        contacts.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut group_evidence = evidence_by_group.remove(&root).unwrap_or_else(Vec::new);
+        group_evidence.sort_by(|a, b| (a.kind, &a.paths).cmp(&(b.kind, &b.paths)));
+        rv.push(DuplicateGroup { contacts: contacts, evidence: group_evidence });
+    };
+    rv.sort_by(|a, b| a.contacts[0].path.cmp(&b.contacts[0].path));
+    rv
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use super::*;
+
+    fn candidate(file: &str, name: &str, emails: &[&str], tels: &[&str]) -> Candidate {
+        Candidate {
+            contact: DuplicateContact {
+                path: path::PathBuf::from(format!("/c/{}.vcf", file)),
+                name: name.to_owned(),
+                uid: file.to_owned(),
+                collection: "c".to_owned()
+            },
+            emails: emails.iter().map(|x| x.to_string()).collect(),
+            tels: tels.iter().map(|x| x.to_string()).collect(),
+            names: vec![name.to_owned()]
+        }
+    }
+
+    /// The files of every group, and the kinds of its evidence.
+    fn summary(groups: &[DuplicateGroup]) -> Vec<(Vec<String>, Vec<&'static str>)> {
+        groups.iter()
+            .map(|group| (
+                group.contacts.iter().map(|x| x.uid.clone()).collect(),
+                group.evidence.iter().map(|x| x.kind.as_str()).collect()
+            ))
+            .collect()
+    }
+
+    fn files(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn group_by_email_and_tel() {
+        let groups = group_candidates(vec![
+            candidate("a", "Anna Müller", &["anna@example.com"], &[]),
+            candidate("b", "A. M.", &["anna@example.com"], &["+4930123"]),
+            candidate("c", "Bob", &[], &["+4930123"]),
+            candidate("d", "Dora", &["dora@example.com"], &[])
+        ]);
+        assert_eq!(summary(&groups), vec![
+            (files(&["a", "b", "c"]), vec!["email", "tel"])
+        ]);
+        assert_eq!(groups[0].evidence[0].values, vec!["anna@example.com".to_owned()]);
+        assert_eq!(groups[0].evidence[1].paths, vec![
+            path::PathBuf::from("/c/b.vcf"),
+            path::PathBuf::from("/c/c.vcf")
+        ]);
+    }
+
+    #[test]
+    fn group_by_long_names() {
+        let groups = group_candidates(vec![
+            candidate("a", "Anna Müller", &[], &[]),
+            candidate("b", "muller, anna", &[], &[]),
+            candidate("c", "Anna Mueller", &[], &[]),
+            candidate("d", "Hanna Schmidt", &[], &[])
+        ]);
+        assert_eq!(summary(&groups), vec![
+            (files(&["a", "b", "c"]), vec!["name"])
+        ]);
+        assert_eq!(groups[0].evidence[0].values, vec![
+            "Anna Müller".to_owned(),
+            "muller, anna".to_owned(),
+            "Anna Mueller".to_owned()
+        ]);
+    }
+
+    #[test]
+    fn short_names_need_another_signal() {
+        let groups = group_candidates(vec![
+            candidate("a", "Anna", &[], &[]),
+            candidate("b", "Anna", &[], &[]),
+            candidate("c", "Ana", &["anna@example.com"], &[]),
+            candidate("d", "Anna", &["anna@example.com"], &[]),
+            candidate("e", "Tom", &[], &["+4930123"]),
+            candidate("f", "Tim", &[], &["+4930123"])
+        ]);
+        assert_eq!(summary(&groups), vec![
+            (files(&["c", "d"]), vec!["email", "name"]),
+            (files(&["e", "f"]), vec!["tel"])
+        ]);
+        assert_eq!(groups[0].evidence[1].values, vec!["Ana".to_owned(), "Anna".to_owned()]);
+    }
+
+    #[test]
+    fn similar_name_keys() {
+        let mut buffer = vec![];
+        let mut similar = |a: &str, b: &str| {
+            let a: Vec<char> = a.chars().collect();
+            let b: Vec<char> = b.chars().collect();
+            similar_names(&a[..], &b[..], &mut buffer)
+        };
+        assert!(similar("anna", "ana"));
+        assert!(similar("ana", "anna"));
+        assert!(!similar("tom", "tim"));
+        assert!(similar("anna", "anja"));
+        assert!(!similar("anna", "anke"));
+        assert!(similar("anna muller", "anna mueller"));
+        assert!(!similar("anna muller", "anne mueller"));
+    }
+
+    #[test]
+    fn json_strings() {
+        assert_eq!(json_string("Anna"), "\"Anna\"");
+        assert_eq!(json_string("Anna \"Q\" Test"), "\"Anna \\\"Q\\\" Test\"");
+        assert_eq!(json_string("a\\b"), "\"a\\\\b\"");
+        assert_eq!(json_string("a\nb\tc\u{1}"), "\"a\\nb\\tc\\u0001\"");
+        assert_eq!(json_string("Müller"), "\"Müller\"");
+    }
+
+    #[test]
+    fn json_groups() {
+        assert_eq!(groups_to_json(&[]), "[]");
+        let group = DuplicateGroup {
+            contacts: vec![DuplicateContact {
+                path: path::PathBuf::from("/c/a.vcf"),
+                name: "Anna".to_owned(),
+                uid: "a".to_owned(),
+                collection: "c".to_owned()
+            }],
+            evidence: vec![Evidence {
+                kind: EvidenceKind::Email,
+                values: vec!["anna@example.com".to_owned()],
+                paths: vec![path::PathBuf::from("/c/a.vcf")]
+            }]
+        };
+        assert_eq!(groups_to_json(&[group]), "[
+  {
+    \"contacts\": [
+      {\"path\": \"/c/a.vcf\", \"name\": \"Anna\", \"uid\": \"a\", \"collection\": \"c\"}
+    ],
+    \"evidence\": [
+      {\"kind\": \"email\", \"values\": [\"anna@example.com\"], \"paths\": [\"/c/a.vcf\"]}
+    ]
+  }
+]");
+    }
+}
@@ -1,4 +1,5 @@
 use std::fs;
+use std::io;
 use std::io::{Read,Write};
 use std::process;
 use std::path::Path;
@@ -7,6 +8,9 @@ use vobject;
 
 use atomicwrites;
 
+use cli::Configuration;
+use lock::Lock;
+
 use cursive::Cursive;
 use cursive::theme;
 use cursive::theme::Color::*;
@@ -16,7 +20,7 @@ mod widgets;
 
 use self::widgets::VcardEditor;
 
-pub fn cli_main<P: AsRef<Path>>(filename: P) {
+pub fn cli_main<P: AsRef<Path>>(config: &Configuration, filename: P) -> io::Result<()> {
     let mut vobj = {
         let mut f = fs::File::open(&filename).unwrap();
         let mut s = String::new();
@@ -56,6 +60,8 @@ pub fn cli_main<P: AsRef<Path>>(filename: P) {
     vobj = editor.to_vobject(&mut siv);
     drop(siv);  // Necessary to be able to write text immediately afterwards
 
+    let _lock = try!(Lock::acquire(config));
     let af = atomicwrites::AtomicFile::new(filename, atomicwrites::AllowOverwrite);
-    af.write(|mut f| f.write_all(vobject::write_component(&vobj).as_bytes())).unwrap();
+    try!(af.write(|mut f| f.write_all(vobject::write_component(&vobj).as_bytes())));
+    Ok(())
 }
@@ -4,8 +4,28 @@ extern crate uuid;
 extern crate atomicwrites;
 extern crate clap;
 extern crate cursive;
+extern crate unicode_normalization;
+extern crate caseless;
+extern crate notify;
+extern crate rayon;
+extern crate fs2;
+extern crate regex;
+#[cfg(feature = "sqlite")]
+#[macro_use]
+extern crate rusqlite;
 
 pub mod app;
 pub mod cli;
 mod utils;
 mod editor;
+mod duplicates;
+pub mod matcher;
+mod merge;
+mod query;
+mod lock;
+mod show;
+mod trash;
+mod usage;
+#[cfg(feature = "sqlite")]
+mod sqlite_index;
+mod watch;
@@ -0,0 +1,60 @@
+//! Advisory locking between mates processes. Whoever writes to the index, the usage file or a
+//! contact directory holds an exclusive lock on a file next to the index (`$MATES_INDEX.lock`)
+//! while doing so. Locks are not reentrant, so functions that take the lock must not call each
+//! other while holding it.
+
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::{Duration,Instant};
+
+use fs2::FileExt;
+
+use cli::Configuration;
+
+/// How long to wait for another process to release the lock. Rebuilding the index of a large
+/// addressbook should take much less than this.
+const LOCK_TIMEOUT_SECS: u64 = 30;
+
+/// How often to try to take the lock while waiting.
+const LOCK_POLL_INTERVAL_MS: u64 = 50;
+
+/// An exclusive lock, released when dropped.
+pub struct Lock {
+    file: fs::File
+}
+
+impl Lock {
+    /// Wait until no other process holds the lock, then take it.
+    pub fn acquire(config: &Configuration) -> io::Result<Lock> {
+        let file = try!(fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .open(&config.lock_path));
+        let start = Instant::now();
+
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Ok(Lock { file: file }),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e)
+            };
+
+            if start.elapsed() >= Duration::from_secs(LOCK_TIMEOUT_SECS) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Timed out after {} seconds waiting for another mates process to finish \
+                             writing (lock file: {}).",
+                            LOCK_TIMEOUT_SECS, config.lock_path.display())
+                ));
+            };
+            thread::sleep(Duration::from_millis(LOCK_POLL_INTERVAL_MS));
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
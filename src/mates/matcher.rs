@@ -0,0 +1,151 @@
+//! How queries are matched against the index. The matcher is chosen by name with `--match` or
+//! `MATES_MATCH`. Programs using mates as a library can add their own by implementing `Matcher`,
+//! passing it to `Configuration::register_matcher` and running the commands with `cli::run`.
+
+use std::io;
+
+use regex::{Regex,RegexBuilder};
+
+use cli::Configuration;
+use query;
+use utils;
+pub use utils::{IndexHeader,IndexItem};
+
+/// Used if neither `--match` nor `MATES_MATCH` is given and `MATES_GREP` isn't set. `mutt-query`
+/// uses `FUZZY` instead.
+pub const SUBSTRING: &'static str = "substring";
+pub const REGEX: &'static str = "regex";
+pub const FUZZY: &'static str = "fuzzy";
+/// Used if `MATES_GREP` is set and no other matcher is chosen.
+pub const COMMAND: &'static str = "command";
+
+pub trait Matcher {
+    /// Return the items of the index that match `query`, each with a score. Results are sorted by
+    /// score where it matters, higher is better. Items of all collections should be returned,
+    /// `--collection` is applied afterwards.
+    fn find(&self, config: &Configuration, query: &str) -> io::Result<Vec<(u32, IndexItem)>>;
+}
+
+/// Read the index, for matchers that search it themselves.
+pub fn load_index(config: &Configuration) -> io::Result<(IndexHeader, Vec<IndexItem>)> {
+    try!(utils::open_index(config)).load()
+}
+
+/// The matchers that are always available, by name.
+pub fn builtin_matchers() -> Vec<(String, Box<Matcher>)> {
+    vec![
+        (SUBSTRING.to_owned(), Box::new(SubstringMatcher) as Box<Matcher>),
+        (REGEX.to_owned(), Box::new(RegexMatcher) as Box<Matcher>),
+        (FUZZY.to_owned(), Box::new(FuzzyMatcher) as Box<Matcher>),
+        (COMMAND.to_owned(), Box::new(CommandMatcher) as Box<Matcher>)
+    ]
+}
+
+/// Every term of the query has to be contained in the item, see the `query` module for the syntax.
+pub struct SubstringMatcher;
+
+impl Matcher for SubstringMatcher {
+    fn find(&self, config: &Configuration, query: &str) -> io::Result<Vec<(u32, IndexItem)>> {
+        let (header, items) = try!(load_index(config));
+        let query = try!(utils::parse_query(config, query, &header));
+        Ok(items.into_iter()
+           .filter(|item| query.matches(item))
+           .map(|item| (0, item))
+           .collect())
+    }
+}
+
+/// Like `SubstringMatcher`, but terms also match with a typo or two, and better matches get higher
+/// scores.
+pub struct FuzzyMatcher;
+
+impl Matcher for FuzzyMatcher {
+    fn find(&self, config: &Configuration, query: &str) -> io::Result<Vec<(u32, IndexItem)>> {
+        let (header, items) = try!(load_index(config));
+        let query = try!(utils::parse_query(config, query, &header));
+        Ok(items.into_iter()
+           .filter_map(|item| query.score(&item).map(|score| (score, item)))
+           .collect())
+    }
+}
+
+/// The query is a case-insensitive regular expression that has to match one of the values other
+/// matchers search in, see `query::searched_values`. Like for them, accents are ignored: values
+/// are also tried with `query::fold` applied, so `muller` finds "Müller".
+pub struct RegexMatcher;
+
+fn regex_matches(regex: &Regex, item: &IndexItem) -> bool {
+    query::searched_values(item).iter()
+        .any(|x| regex.is_match(&x[..]) || regex.is_match(&query::fold(&x[..])[..]))
+}
+
+impl Matcher for RegexMatcher {
+    fn find(&self, config: &Configuration, query: &str) -> io::Result<Vec<(u32, IndexItem)>> {
+        let regex = match RegexBuilder::new(query).case_insensitive(true).build() {
+            Ok(x) => x,
+            Err(e) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid regular expression: {}", e)
+            ))
+        };
+
+        let (_, items) = try!(load_index(config));
+        Ok(items.into_iter()
+           .filter(|item| regex_matches(&regex, item))
+           .map(|item| (0, item))
+           .collect())
+    }
+}
+
+/// Runs the command given in `MATES_GREP` with the query and the path of the index file, and
+/// returns the lines it prints.
+pub struct CommandMatcher;
+
+impl Matcher for CommandMatcher {
+    fn find(&self, config: &Configuration, query: &str) -> io::Result<Vec<(u32, IndexItem)>> {
+        let grep_cmd = match config.grep_cmd {
+            Some(ref x) => x,
+            None => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The command matcher needs MATES_GREP to be set."
+            ))
+        };
+        Ok(try!(utils::grep_index_query(config, &grep_cmd[..], query))
+           .map(|item| (0, item))
+           .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use regex::RegexBuilder;
+
+    use super::*;
+
+    #[test]
+    fn regex_matches_like_other_matchers() {
+        let anna = IndexItem {
+            email: "anna@example.com".to_owned(),
+            name: "Anna Müller".to_owned(),
+            filepath: Some(path::PathBuf::from("/contacts/smith.vcf")),
+            stamp: None,
+            collection: String::new(),
+            uid: String::new(),
+            tels: vec!["+4930123".to_owned()],
+            fields: vec![("ORG".to_owned(), vec!["ACME Corp".to_owned()])]
+        };
+        let matches = |pattern: &str| {
+            regex_matches(&RegexBuilder::new(pattern).case_insensitive(true).build().unwrap(), &anna)
+        };
+        assert!(matches("^anna m"));
+        assert!(matches("MÜLLER"));
+        assert!(matches("muller$"));
+        assert!(matches("@example\\.com"));
+        assert!(matches("^acme"));
+        assert!(matches("30123"));
+        assert!(!matches("smith"));
+        assert!(!matches("^muller"));
+    }
+}
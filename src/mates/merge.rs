@@ -0,0 +1,225 @@
+//! Combining several contacts of the same person into one, see `mates merge`. The merged contact
+//! keeps the file and UID of the first one. Properties a person can have several of are combined,
+//! for all other properties one of the contacts' values has to be chosen if they differ.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path;
+
+use vobject::{Component,Property,escape_chars};
+
+use query;
+use usage;
+use utils;
+use utils::Contact;
+
+/// Properties of which all values are kept. Values that are the same are only kept once, with
+/// the TYPEs of all of them.
+const MULTI_VALUED: &'static [&'static str] = &["EMAIL", "TEL", "ADR", "URL", "IMPP"];
+
+/// Properties whose value is a list. The merged contact has one such property with all values.
+const LIST_VALUED: &'static [&'static str] = &["CATEGORIES", "NICKNAME"];
+
+/// Properties that are taken from the first contact, if it has them.
+const KEPT: &'static [&'static str] = &["VERSION", "UID", "PRODID", "REV"];
+
+/// How to choose between differing values of a property without asking.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Prefer {
+    First,
+    Last,
+    /// The contact whose file was modified last.
+    Newest
+}
+
+/// The values of a property, see `merge_contacts`.
+pub struct ConflictOption {
+    /// All contacts with these values.
+    pub paths: Vec<path::PathBuf>,
+    pub props: Vec<Property>
+}
+
+/// The order in which `prefer` picks the contacts' values, as indices into `contacts`.
+pub fn preference_order(contacts: &[Contact], prefer: Prefer) -> io::Result<Vec<usize>> {
+    let mut rv: Vec<usize> = (0..contacts.len()).collect();
+    match prefer {
+        Prefer::First => (),
+        Prefer::Last => rv.reverse(),
+        Prefer::Newest => {
+            let mut mtimes = vec![];
+            for contact in contacts.iter() {
+                mtimes.push(try!(try!(fs::metadata(&contact.path)).modified()));
+            };
+            // Stable, so of contacts modified at the same time the first is preferred.
+            rv.sort_by(|&a, &b| mtimes[b].cmp(&mtimes[a]));
+        }
+    };
+    Ok(rv)
+}
+
+/// TYPE values of a property, in their original order.
+fn types(prop: &Property) -> Vec<String> {
+    prop.params.iter()
+        .filter(|&(key, _)| key.eq_ignore_ascii_case("TYPE"))
+        .flat_map(|(_, value)| value.split(',').map(|x| x.trim().trim_matches('"').to_owned()))
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+/// Add the TYPEs of `other` that `prop` doesn't have yet.
+fn add_types(prop: &mut Property, other: &Property) {
+    let mut all = types(prop);
+    let old_len = all.len();
+    for t in types(other) {
+        if !all.iter().any(|x| x.eq_ignore_ascii_case(&t[..])) {
+            all.push(t);
+        };
+    };
+    if all.len() == old_len {
+        return;
+    };
+    let key = prop.params.keys()
+        .find(|x| x.eq_ignore_ascii_case("TYPE"))
+        .cloned()
+        .unwrap_or_else(|| "TYPE".to_owned());
+    prop.params.insert(key, all.join(","));
+}
+
+/// What two values of a multi-valued property have to share to be considered the same.
+fn value_key(prop: &Property, country_prefix: Option<&str>) -> String {
+    let value = prop.value_as_string();
+    match &prop.name[..] {
+        "EMAIL" => usage::normalize_email(&value[..]),
+        "TEL" => match utils::normalize_tel(&value[..], country_prefix) {
+            ref x if x.is_empty() => value.trim().to_owned(),
+            x => x
+        },
+        _ => query::fold(value.trim())
+    }
+}
+
+/// Merge the contacts into one, based on the first. `choose` is called for every property that
+/// differs between contacts, with its different values and the contacts that have each of them,
+/// and returns the index of the option to use.
+pub fn merge_contacts<F>(contacts: &[Contact], country_prefix: Option<&str>, mut choose: F) -> io::Result<Component>
+    where F: FnMut(&str, &[ConflictOption]) -> io::Result<usize>
+{
+    let mut rv = match contacts.first() {
+        Some(x) => x.component.clone(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Nothing to merge."))
+    };
+
+    let names: BTreeSet<String> = contacts.iter()
+        .flat_map(|x| x.component.props.keys().cloned())
+        .collect();
+
+    for name in names {
+        let all_props: Vec<&Property> = contacts.iter()
+            .flat_map(|x| x.component.get_all(&name[..]).iter())
+            .collect();
+
+        if KEPT.contains(&&name[..]) {
+            if rv.get_all(&name[..]).is_empty() {
+                rv.set(all_props[0].clone());
+            };
+        } else if MULTI_VALUED.contains(&&name[..]) {
+            let mut merged: Vec<Property> = vec![];
+            let mut keys: Vec<String> = vec![];
+            for prop in all_props {
+                let key = value_key(prop, country_prefix);
+                match keys.iter().position(|x| *x == key) {
+                    Some(i) => add_types(&mut merged[i], prop),
+                    None => {
+                        keys.push(key);
+                        merged.push(prop.clone());
+                    }
+                };
+            };
+            rv.remove(&name[..]);
+            for prop in merged {
+                rv.push(prop);
+            };
+        } else if LIST_VALUED.contains(&&name[..]) {
+            let mut values: Vec<String> = vec![];
+            let mut keys: Vec<String> = vec![];
+            for prop in all_props.iter() {
+                for value in utils::split_raw_value(&prop.raw_value[..], ',') {
+                    let value = value.trim().to_owned();
+                    let key = query::fold(&value[..]);
+                    if !value.is_empty() && !keys.contains(&key) {
+                        keys.push(key);
+                        values.push(value);
+                    };
+                };
+            };
+            let mut prop = all_props[0].clone();
+            prop.raw_value = values.iter().map(|x| escape_chars(&x[..])).collect::<Vec<_>>().join(",");
+            rv.remove(&name[..]);
+            rv.push(prop);
+        } else {
+            // The values of each contact that has the property, without repetitions.
+            let mut options: Vec<ConflictOption> = vec![];
+            for contact in contacts.iter() {
+                let props = contact.component.get_all(&name[..]);
+                if props.is_empty() {
+                    continue;
+                };
+                let same = |option: &ConflictOption| {
+                    option.props.len() == props.len() &&
+                        option.props.iter().zip(props.iter()).all(|(a, b)| a.raw_value.trim() == b.raw_value.trim())
+                };
+                match options.iter().position(same) {
+                    Some(i) => options[i].paths.push(contact.path.clone()),
+                    None => options.push(ConflictOption { paths: vec![contact.path.clone()], props: props.to_vec() })
+                };
+            };
+
+            let chosen = if options.len() > 1 {
+                let i = try!(choose(&name[..], &options[..]));
+                if i >= options.len() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid choice."));
+                };
+                i
+            } else {
+                0
+            };
+            rv.remove(&name[..]);
+            for prop in options.swap_remove(chosen).props {
+                rv.push(prop);
+            };
+        };
+    };
+    Ok(rv)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use vobject::parse_component;
+
+    use utils::Contact;
+    use super::*;
+
+    fn contact(path: &str, title: &str) -> Contact {
+        let text = format!("BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Anna\r\nTITLE:{}\r\nEND:VCARD\r\n", title);
+        Contact { component: parse_component(&text[..]).unwrap(), path: path::PathBuf::from(path) }
+    }
+
+    #[test]
+    fn options_with_all_paths() {
+        let contacts = vec![contact("a.vcf", "x"), contact("b.vcf", "y"), contact("c.vcf", "x")];
+        let mut calls = 0;
+        let merged = merge_contacts(&contacts[..], None, |name, options| {
+            calls += 1;
+            assert_eq!(name, "TITLE");
+            assert_eq!(options.len(), 2);
+            assert_eq!(options[0].paths, vec![path::PathBuf::from("a.vcf"), path::PathBuf::from("c.vcf")]);
+            assert_eq!(options[1].paths, vec![path::PathBuf::from("b.vcf")]);
+            Ok(1)
+        }).unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(merged.get_only("TITLE").unwrap().raw_value, "y");
+    }
+}
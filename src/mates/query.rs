@@ -0,0 +1,708 @@
+//! A small query language for searching the index.
+//!
+//! Whitespace-separated terms must all match. `OR` between terms and `NOT` or a leading `-` in
+//! front of them work as expected, `AND` is implied but may be given. Terms can be grouped with
+//! parentheses and quoted with `"` to include whitespace or keywords. A term may be prefixed with
+//! `name:`, `email:`, `org:`, `nick:`, `tel:` or `path:` to only search that field, otherwise it
+//! is searched for in every field except the path. All matching ignores case and diacritics, see
+//! `fold`.
+//!
+//! `Query::score` additionally tolerates typos and rates how well an item matches, which is used
+//! to sort results for address completion.
+
+use std::cmp;
+use std::io;
+
+use caseless;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+use utils::{IndexItem,normalize_tel,strip_tel};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Field {
+    Name,
+    Email,
+    Org,
+    Nick,
+    Tel,
+    Path,
+    /// Every field except the path.
+    Any
+}
+
+impl Field {
+    fn from_prefix(prefix: &str) -> Option<Field> {
+        match &prefix.to_lowercase()[..] {
+            "name" => Some(Field::Name),
+            "email" => Some(Field::Email),
+            "org" => Some(Field::Org),
+            "nick" => Some(Field::Nick),
+            "tel" => Some(Field::Tel),
+            "path" => Some(Field::Path),
+            _ => None
+        }
+    }
+
+    /// The vCard property that has to be in `MATES_INDEX_FIELDS` for this field to be searchable.
+    pub fn index_field(&self) -> Option<&'static str> {
+        match *self {
+            Field::Org => Some("ORG"),
+            Field::Nick => Some("NICKNAME"),
+            _ => None
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Query {
+    /// The field contains the text, which is already folded.
+    Term(Field, String),
+    /// One of the phone numbers of the item contains the number. Both are normalized with the
+    /// country prefix, see `utils::normalize_tel`.
+    Tel(String, Option<String>),
+    Not(Box<Query>),
+    /// All subqueries match. Matches everything if empty.
+    And(Vec<Query>),
+    /// Any subquery matches.
+    Or(Vec<Query>)
+}
+
+impl Query {
+    pub fn parse(s: &str) -> io::Result<Query> {
+        let tokens = try!(tokenize(s));
+        let mut parser = Parser { tokens: tokens, pos: 0 };
+        let rv = try!(parser.parse_or());
+        match parser.tokens.get(parser.pos) {
+            None => Ok(rv),
+            Some(_) => Err(query_error("Unmatched closing parenthesis."))
+        }
+    }
+
+    pub fn matches(&self, item: &IndexItem) -> bool {
+        match *self {
+            Query::Term(field, ref text) => field_values(item, field).iter()
+                .any(|x| fold(&x[..]).contains(&text[..])),
+            Query::Tel(ref number, ref country_prefix) => item.tels.iter()
+                .any(|x| normalize_tel(&x[..], country_prefix.as_ref().map(|x| &x[..])).contains(&number[..])),
+            Query::Not(ref query) => !query.matches(item),
+            Query::And(ref queries) => queries.iter().all(|x| x.matches(item)),
+            Query::Or(ref queries) => queries.iter().any(|x| x.matches(item))
+        }
+    }
+
+    /// Like `matches`, but terms also match with a few typos. Returns how well the item matches,
+    /// higher is better, or `None` if it doesn't match at all. Negated terms are never fuzzy.
+    pub fn score(&self, item: &IndexItem) -> Option<u32> {
+        match *self {
+            Query::Term(field, ref text) => score_term(item, field, &text[..]),
+            Query::Tel(..) => if self.matches(item) { Some(SCORE_SUBSTRING) } else { None },
+            Query::Not(ref query) => if query.matches(item) { None } else { Some(0) },
+            Query::And(ref queries) => {
+                let mut rv = 0;
+                for query in queries.iter() {
+                    match query.score(item) {
+                        Some(x) => rv += x,
+                        None => return None
+                    };
+                };
+                Some(rv)
+            },
+            Query::Or(ref queries) => queries.iter().filter_map(|x| x.score(item)).max()
+        }
+    }
+
+    /// Normalize the numbers of `tel:` terms with the given country prefix, and use it for the
+    /// numbers of the items as well.
+    pub fn set_country_prefix(&mut self, country_prefix: Option<&str>) {
+        match *self {
+            Query::Tel(ref mut number, ref mut prefix) => {
+                *number = normalize_tel(&number[..], country_prefix);
+                *prefix = country_prefix.map(|x| x.to_owned());
+            },
+            Query::Term(..) => (),
+            Query::Not(ref mut query) => query.set_country_prefix(country_prefix),
+            Query::And(ref mut queries) | Query::Or(ref mut queries) => for query in queries.iter_mut() {
+                query.set_country_prefix(country_prefix);
+            }
+        }
+    }
+
+    /// All fields the query searches in explicitly.
+    pub fn fields(&self) -> Vec<Field> {
+        let mut rv = vec![];
+        self.collect_fields(&mut rv);
+        rv
+    }
+
+    fn collect_fields(&self, rv: &mut Vec<Field>) {
+        match *self {
+            Query::Term(field, _) => if !rv.contains(&field) { rv.push(field) },
+            Query::Tel(..) => if !rv.contains(&Field::Tel) { rv.push(Field::Tel) },
+            Query::Not(ref query) => query.collect_fields(rv),
+            Query::And(ref queries) | Query::Or(ref queries) => for query in queries.iter() {
+                query.collect_fields(rv);
+            }
+        }
+    }
+}
+
+/// Bring a string into a form in which it can be compared to others regardless of case, diacritics
+/// and different Unicode representations of the same text: Apply compatibility decomposition
+/// (NFKD) and case folding, then remove all combining marks. "Müller" becomes "muller".
+pub fn fold(s: &str) -> String {
+    let folded = caseless::default_case_fold_str(&s.nfkd().collect::<String>()[..]);
+    folded.nfkd().filter(|&c| !is_combining_mark(c)).collect()
+}
+
+/// The values a term without a field prefix is searched in.
+pub fn searched_values(item: &IndexItem) -> Vec<String> {
+    field_values(item, Field::Any)
+}
+
+fn field_values(item: &IndexItem, field: Field) -> Vec<String> {
+    match field {
+        Field::Name => vec![item.name.clone()],
+        Field::Email => vec![item.email.clone()],
+        Field::Path => match item.filepath {
+            Some(ref x) => vec![x.display().to_string()],
+            None => vec![]
+        },
+        Field::Tel => item.tels.clone(),
+        Field::Any => {
+            let mut rv = vec![item.name.clone(), item.email.clone()];
+            rv.extend(item.tels.iter().cloned());
+            for &(_, ref values) in item.fields.iter() {
+                rv.extend(values.iter().cloned());
+            };
+            rv
+        },
+        _ => match field.index_field() {
+            Some(x) => item.field(x).to_vec(),
+            None => vec![]
+        }
+    }
+}
+
+// Scores for the different ways a term can match, see `score_term`.
+const SCORE_NAME_PREFIX: u32 = 100;
+const SCORE_WORD_PREFIX: u32 = 90;
+const SCORE_FIELD_PREFIX: u32 = 60;
+const SCORE_SUBSTRING: u32 = 40;
+const SCORE_FUZZY: u32 = 30;
+const SCORE_PER_TYPO: u32 = 10;
+
+/// Rate how well `text` matches the given field of the item. Prefix matches on the name, its words
+/// and the local part of the email address are rated highest, then prefix matches on other
+/// fields, then matches anywhere, then matches with typos.
+fn score_term(item: &IndexItem, field: Field, text: &str) -> Option<u32> {
+    if text.is_empty() {
+        return if field_values(item, field).is_empty() { None } else { Some(0) };
+    };
+
+    let name = fold(&item.name[..]);
+    let email = fold(&item.email[..]);
+    let local_part = email.split('@').next().unwrap_or("").to_owned();
+    let others: Vec<String> = match field {
+        // Everything `field_values` has apart from name and email, which are rated separately.
+        Field::Any => item.tels.iter()
+            .chain(item.fields.iter().flat_map(|&(_, ref values)| values.iter()))
+            .map(|x| fold(&x[..]))
+            .collect(),
+        Field::Name | Field::Email => vec![],
+        _ => field_values(item, field).iter().map(|x| fold(&x[..])).collect()
+    };
+    let use_name = field == Field::Any || field == Field::Name;
+    let use_email = field == Field::Any || field == Field::Email;
+
+    if field == Field::Path {
+        return if field_values(item, field).iter().any(|x| fold(&x[..]).contains(text)) {
+            Some(SCORE_SUBSTRING)
+        } else {
+            None
+        };
+    };
+
+    if use_name && name.starts_with(text) {
+        return Some(SCORE_NAME_PREFIX);
+    };
+    if (use_name && words(&name[..]).any(|x| x.starts_with(text))) ||
+        (use_email && (local_part.starts_with(text) || words(&local_part[..]).any(|x| x.starts_with(text)))) {
+        return Some(SCORE_WORD_PREFIX);
+    };
+    if others.iter().any(|x| x.starts_with(text) || words(&x[..]).any(|x| x.starts_with(text))) {
+        return Some(SCORE_FIELD_PREFIX);
+    };
+    if (use_name && name.contains(text)) || (use_email && email.contains(text)) ||
+        others.iter().any(|x| x.contains(text)) {
+        return Some(SCORE_SUBSTRING);
+    };
+
+    let max_typos = match text.chars().count() {
+        x if x < 3 => return None,
+        x if x < 6 => 1,
+        _ => 2
+    };
+    let mut candidates: Vec<&str> = vec![];
+    if use_name {
+        candidates.extend(words(&name[..]));
+    };
+    if use_email {
+        candidates.extend(words(&local_part[..]));
+    };
+    for value in others.iter() {
+        candidates.extend(words(&value[..]));
+    };
+
+    candidates.into_iter()
+        .map(|x| prefix_edit_distance(text, x))
+        .min()
+        .and_then(|typos| if typos <= max_typos {
+            Some(SCORE_FUZZY - SCORE_PER_TYPO * typos as u32)
+        } else {
+            None
+        })
+}
+
+/// The alphanumeric words of `s`.
+pub fn words<'a>(s: &'a str) -> Box<Iterator<Item=&'a str> + 'a> {
+    Box::new(s.split(|c: char| !c.is_alphanumeric()).filter(|x| !x.is_empty()))
+}
+
+/// The smallest number of insertions, deletions, substitutions and transpositions of adjacent
+/// characters needed to turn `needle` into any prefix of `haystack`.
+fn prefix_edit_distance(needle: &str, haystack: &str) -> usize {
+    *edit_distances(needle, haystack).last().unwrap().iter().min().unwrap()
+}
+
+/// Whether `a` can be turned into `b` with at most `max` edits, counted like in
+/// `prefix_edit_distance`. Faster than computing the distance, because only the cells of the
+/// matrix close to its diagonal are needed, and it stops as soon as a row exceeds `max`. `buffer`
+/// can be reused between calls, so nothing is allocated.
+pub fn within_edit_distance(a: &[char], b: &[char], max: usize, buffer: &mut Vec<usize>) -> bool {
+    let len_diff = if a.len() > b.len() { a.len() - b.len() } else { b.len() - a.len() };
+    if len_diff > max {
+        return false;
+    };
+
+    // Rows i - 2, i - 1 and i of the matrix in `edit_distances` take turns in `buffer`. Only the
+    // cells at most `max` from the diagonal are computed, the ones next to them are `max + 1`.
+    let width = b.len() + 1;
+    buffer.clear();
+    buffer.resize(3 * width, max + 1);
+    for j in 0..cmp::min(width, max + 1) {
+        buffer[j] = j;
+    };
+    for i in 1..a.len() + 1 {
+        let row = (i % 3) * width;
+        let prev = ((i + 2) % 3) * width;
+        let prev2 = ((i + 1) % 3) * width;
+        let first = cmp::max(1, i.saturating_sub(max));
+        buffer[row] = cmp::min(i, max + 1);
+        // Still holds row i - 3 otherwise.
+        if first > 1 {
+            buffer[row + first - 1] = max + 1;
+        };
+        let mut row_min = buffer[row];
+        for j in first..cmp::min(width, i + max + 1) {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut d = cmp::min(cmp::min(buffer[prev + j] + 1, buffer[row + j - 1] + 1), buffer[prev + j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = cmp::min(d, buffer[prev2 + j - 2] + 1);
+            };
+            buffer[row + j] = d;
+            row_min = cmp::min(row_min, d);
+        };
+        if row_min > max {
+            return false;
+        };
+    };
+    buffer[(a.len() % 3) * width + b.len()] <= max
+}
+
+/// The edit distances between all prefixes of `a` and all prefixes of `b`.
+fn edit_distances(a: &str, b: &str) -> Vec<Vec<usize>> {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // d[i][j] is the distance between a[..i] and b[..j]
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in 0..a.len() + 1 {
+        d[i][0] = i;
+    };
+    for j in 0..b.len() + 1 {
+        d[0][j] = j;
+    };
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            d[i][j] = *[d[i - 1][j] + 1, d[i][j - 1] + 1, d[i - 1][j - 1] + cost].iter().min().unwrap();
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = cmp::min(d[i][j], d[i - 2][j - 2] + 1);
+            };
+        };
+    };
+    d
+}
+
+fn query_error(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid query: {}", msg))
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+enum Token {
+    /// A search term and the position of its first quoted character, if any. Quoted terms are
+    /// never keywords.
+    Word(String, Option<usize>),
+    Not,
+    LParen,
+    RParen
+}
+
+fn tokenize(s: &str) -> io::Result<Vec<Token>> {
+    let mut rv = vec![];
+    let mut chars = s.chars().peekable();
+
+    loop {
+        let c = match chars.peek() {
+            Some(&x) => x,
+            None => break
+        };
+
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if c == '(' {
+            chars.next();
+            rv.push(Token::LParen);
+            continue;
+        } else if c == ')' {
+            chars.next();
+            rv.push(Token::RParen);
+            continue;
+        } else if c == '-' {
+            chars.next();
+            match chars.peek() {
+                Some(&x) if !x.is_whitespace() && x != ')' => rv.push(Token::Not),
+                _ => rv.push(Token::Word("-".to_owned(), None))
+            };
+            continue;
+        };
+
+        let mut word = String::new();
+        let mut first_quote = None;
+        loop {
+            match chars.peek() {
+                Some(&x) if x.is_whitespace() || x == '(' || x == ')' => break,
+                None => break,
+                _ => ()
+            };
+            match chars.next() {
+                Some('"') => {
+                    if first_quote.is_none() {
+                        first_quote = Some(word.len());
+                    };
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some(x) => word.push(x),
+                            None => return Err(query_error("Unterminated quote."))
+                        };
+                    };
+                },
+                Some(x) => word.push(x),
+                None => break
+            };
+        };
+        rv.push(Token::Word(word, first_quote));
+    };
+    Ok(rv)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl Parser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(&Token::Word(ref x, None)) => x == keyword,
+            _ => false
+        }
+    }
+
+    fn parse_or(&mut self) -> io::Result<Query> {
+        let mut rv = vec![try!(self.parse_and())];
+        while self.peek_keyword("OR") {
+            self.pos += 1;
+            rv.push(try!(self.parse_and()));
+        };
+        Ok(if rv.len() == 1 { rv.pop().unwrap() } else { Query::Or(rv) })
+    }
+
+    fn parse_and(&mut self) -> io::Result<Query> {
+        let mut rv = vec![];
+        loop {
+            match self.tokens.get(self.pos) {
+                None | Some(&Token::RParen) => break,
+                _ if self.peek_keyword("OR") => break,
+                _ if self.peek_keyword("AND") => {
+                    self.pos += 1;
+                    continue;
+                },
+                _ => rv.push(try!(self.parse_unary()))
+            };
+        };
+        Ok(if rv.len() == 1 { rv.pop().unwrap() } else { Query::And(rv) })
+    }
+
+    fn parse_unary(&mut self) -> io::Result<Query> {
+        let token = match self.tokens.get(self.pos) {
+            Some(x) => x.clone(),
+            None => return Err(query_error("Unexpected end of query."))
+        };
+        self.pos += 1;
+
+        match token {
+            Token::Not => Ok(Query::Not(Box::new(try!(self.parse_unary())))),
+            Token::Word(ref x, None) if x == "NOT" => Ok(Query::Not(Box::new(try!(self.parse_unary())))),
+            Token::LParen => {
+                let rv = try!(self.parse_or());
+                match self.tokens.get(self.pos) {
+                    Some(&Token::RParen) => {
+                        self.pos += 1;
+                        Ok(rv)
+                    },
+                    _ => Err(query_error("Missing closing parenthesis."))
+                }
+            },
+            Token::RParen => Err(query_error("Unmatched closing parenthesis.")),
+            Token::Word(word, first_quote) => Ok(parse_term(&word[..], first_quote))
+        }
+    }
+}
+
+/// Split off the field prefix of a term. Colons inside quotes don't count.
+fn parse_term(word: &str, first_quote: Option<usize>) -> Query {
+    let unquoted = &word[..first_quote.unwrap_or(word.len())];
+    if let Some(i) = unquoted.find(':') {
+        match Field::from_prefix(&word[..i]) {
+            Some(Field::Tel) => return Query::Tel(strip_tel(&word[i + 1..]), None),
+            Some(field) => return Query::Term(field, fold(&word[i + 1..])),
+            None => ()
+        };
+    };
+    Query::Term(Field::Any, fold(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+
+    use utils::IndexItem;
+    use super::*;
+
+    fn term(field: Field, text: &str) -> Query {
+        Query::Term(field, text.to_owned())
+    }
+
+    fn any(text: &str) -> Query {
+        term(Field::Any, text)
+    }
+
+    fn not(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+
+    fn parse(s: &str) -> Query {
+        Query::parse(s).unwrap()
+    }
+
+    fn item(name: &str, email: &str, filepath: &str) -> IndexItem {
+        IndexItem {
+            email: email.to_owned(),
+            name: name.to_owned(),
+            filepath: Some(path::PathBuf::from(filepath)),
+            stamp: None,
+            collection: String::new(),
+            uid: String::new(),
+            tels: vec![],
+            fields: vec![("ORG".to_owned(), vec!["ACME Corp".to_owned()])]
+        }
+    }
+
+    #[test]
+    fn parse_terms() {
+        assert_eq!(parse(""), Query::And(vec![]));
+        assert_eq!(parse("Smith"), any("smith"));
+        assert_eq!(parse("Anna  Müller"), Query::And(vec![any("anna"), any("muller")]));
+    }
+
+    #[test]
+    fn parse_field_prefixes() {
+        assert_eq!(parse("name:Smith"), term(Field::Name, "smith"));
+        assert_eq!(parse("EMAIL:example.com"), term(Field::Email, "example.com"));
+        assert_eq!(parse("org:acme"), term(Field::Org, "acme"));
+        assert_eq!(parse("nick:bobby"), term(Field::Nick, "bobby"));
+        assert_eq!(parse("tel:\"030 / 123-4\""), Query::Tel("0301234".to_owned(), None));
+        assert_eq!(parse("path:work"), term(Field::Path, "work"));
+        // Unknown prefixes are part of the term.
+        assert_eq!(parse("foo:bar"), any("foo:bar"));
+    }
+
+    #[test]
+    fn parse_quotes() {
+        assert_eq!(parse("\"van der Berg\""), any("van der berg"));
+        assert_eq!(parse("name:\"van der\""), term(Field::Name, "van der"));
+        assert_eq!(parse("van\" der\"x"), any("van derx"));
+        // Colons inside quotes don't make a prefix, and quoted keywords are terms.
+        assert_eq!(parse("\"name:x\""), any("name:x"));
+        assert_eq!(parse("\"OR\" \"NOT\""), Query::And(vec![any("or"), any("not")]));
+        assert_eq!(parse("\"a (b)\""), any("a (b)"));
+    }
+
+    #[test]
+    fn parse_negation() {
+        assert_eq!(parse("-smith"), not(any("smith")));
+        assert_eq!(parse("NOT smith"), not(any("smith")));
+        assert_eq!(parse("anna -name:smith"), Query::And(vec![any("anna"), not(term(Field::Name, "smith"))]));
+        assert_eq!(parse("NOT NOT a"), not(not(any("a"))));
+        // A lone dash is a term.
+        assert_eq!(parse("a - b"), Query::And(vec![any("a"), any("-"), any("b")]));
+        // Keywords are case-sensitive.
+        assert_eq!(parse("not a"), Query::And(vec![any("not"), any("a")]));
+    }
+
+    #[test]
+    fn parse_and_or() {
+        assert_eq!(parse("a AND b"), Query::And(vec![any("a"), any("b")]));
+        assert_eq!(parse("a OR b"), Query::Or(vec![any("a"), any("b")]));
+        // AND binds more tightly than OR.
+        assert_eq!(parse("a OR b c"), Query::Or(vec![any("a"), Query::And(vec![any("b"), any("c")])]));
+        assert_eq!(parse("a b OR c"), Query::Or(vec![Query::And(vec![any("a"), any("b")]), any("c")]));
+    }
+
+    #[test]
+    fn parse_parentheses() {
+        assert_eq!(parse("(a OR b) c"), Query::And(vec![Query::Or(vec![any("a"), any("b")]), any("c")]));
+        assert_eq!(parse("-(a b)"), not(Query::And(vec![any("a"), any("b")])));
+        assert_eq!(parse("((a))"), any("a"));
+        assert_eq!(parse("a(b)"), Query::And(vec![any("a"), any("b")]));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Query::parse("\"abc").is_err());
+        assert!(Query::parse("(a").is_err());
+        assert!(Query::parse("a)").is_err());
+        assert!(Query::parse(")").is_err());
+        assert!(Query::parse("NOT").is_err());
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse("a name:b -(org:c OR name:d)").fields(), vec![Field::Any, Field::Name, Field::Org]);
+    }
+
+    #[test]
+    fn matching() {
+        let anna = item("Anna Müller", "anna@example.com", "/contacts/smith.vcf");
+        assert!(parse("müller").matches(&anna));
+        assert!(parse("MULLER anna").matches(&anna));
+        assert!(parse("acme").matches(&anna));
+        assert!(parse("email:example").matches(&anna));
+        assert!(!parse("name:example").matches(&anna));
+        assert!(!parse("-anna").matches(&anna));
+        assert!(parse("bob OR anna").matches(&anna));
+        assert!(!parse("bob OR (anna -muller)").matches(&anna));
+        assert!(parse("").matches(&anna));
+        // The path is only searched if asked for.
+        assert!(!parse("smith").matches(&anna));
+        assert!(parse("path:smith").matches(&anna));
+    }
+
+    #[test]
+    fn matching_tels() {
+        let mut anna = item("Anna Müller", "anna@example.com", "/contacts/a.vcf");
+        anna.tels = vec!["+4930123456".to_owned(), "0170555".to_owned()];
+        let with_prefix = |s: &str| {
+            let mut rv = parse(s);
+            rv.set_country_prefix(Some("+49"));
+            rv
+        };
+        assert!(with_prefix("tel:030123").matches(&anna));
+        assert!(with_prefix("tel:\"(030) 123-456\"").matches(&anna));
+        assert!(with_prefix("tel:+49170555").matches(&anna));
+        assert!(with_prefix("tel:123").matches(&anna));
+        assert!(!with_prefix("tel:040").matches(&anna));
+        assert!(parse("tel:0049301").matches(&anna));
+        assert!(!parse("tel:030123").matches(&anna));
+        // Numbers are searched without a prefix too.
+        assert!(parse("0170").matches(&anna));
+        assert!(parse("tel:030123 OR name:anna").score(&anna).is_some());
+    }
+
+    #[test]
+    fn scoring() {
+        let anna = item("Anna Müller", "anna@example.com", "/contacts/a.vcf");
+        assert!(!parse("mulelr").matches(&anna));
+        assert!(parse("mulelr").score(&anna).is_some());
+        assert!(parse("anna").score(&anna) > parse("muller").score(&anna));
+        assert!(parse("muller").score(&anna) > parse("mulelr").score(&anna));
+        assert_eq!(parse("xyzzy").score(&anna), None);
+        // Negated terms are never fuzzy.
+        assert_eq!(parse("-mulelr").score(&anna), Some(0));
+
+        let mut anna = anna;
+        anna.tels = vec!["+4930123456".to_owned()];
+        assert!(parse("4930123").score(&anna).is_some());
+        assert!(parse("123456").score(&anna).is_some());
+        assert!(parse("anna").score(&anna) > parse("123456").score(&anna));
+        assert_eq!(parse("999999").score(&anna), None);
+    }
+
+    #[test]
+    fn prefix_edit_distances() {
+        assert_eq!(prefix_edit_distance("smi", "smith"), 0);
+        assert_eq!(prefix_edit_distance("smyt", "smith"), 1);
+        assert_eq!(prefix_edit_distance("smiht", "smith"), 1);
+        assert_eq!(prefix_edit_distance("abc", ""), 3);
+    }
+
+    #[test]
+    fn within_edit_distances() {
+        let mut buffer = vec![];
+        let mut within = |a: &str, b: &str, max: usize| {
+            let a: Vec<char> = a.chars().collect();
+            let b: Vec<char> = b.chars().collect();
+            within_edit_distance(&a[..], &b[..], max, &mut buffer)
+        };
+        assert!(within("anna muller", "anna muller", 0));
+        assert!(!within("anna muller", "anna mueller", 0));
+        assert!(within("anna muller", "anna mueller", 1));
+        assert!(within("anna muller", "anna mulelr", 1));
+        assert!(within("anna muller", "bnna muller", 1));
+        assert!(within("anna muller", "nna muller", 1));
+        assert!(!within("anna muller", "anne mueller", 1));
+        assert!(within("anna muller", "anne mueller", 2));
+        assert!(!within("anna muller", "anna mu", 3));
+        assert!(within("", "ab", 2));
+        assert!(!within("ab", "", 1));
+        // The same buffer works for strings of any length.
+        assert!(within("x", "y", 1));
+        assert!(within("ab", "ba", 1));
+
+        // Same results as the whole matrix.
+        let words = ["smith", "smyth", "smiht", "mith", "smithe", "schmidt", "", "s", "htims", "smithsonian",
+                     "smtihsoinan", "msithsonain", "smithsonia"];
+        for a in words.iter() {
+            for b in words.iter() {
+                let distance = *edit_distances(a, b).last().unwrap().last().unwrap();
+                for max in 0..4 {
+                    assert_eq!(within(a, b, max), distance <= max, "{} {} {}", a, b, max);
+                };
+            };
+        };
+    }
+}
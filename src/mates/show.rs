@@ -0,0 +1,124 @@
+//! The readable summary of a contact printed by `mates show`.
+
+use vobject::{Component,Property};
+
+use utils;
+
+/// Width of the labels in `format_contact`.
+const LABEL_WIDTH: usize = 14;
+
+/// A summary of the contact for humans, one property per line, with its TYPEs in parentheses.
+pub fn format_contact(component: &Component) -> String {
+    let mut rv = String::new();
+    {
+        let mut line = |label: &str, value: &str, types: Vec<String>| {
+            let mut value = value.trim().to_owned();
+            if !types.is_empty() {
+                value = format!("{} ({})", value, types.join(", "));
+            };
+            // Continuation lines, like in multi-line notes, are aligned with the first one.
+            let indent = format!("\n{}", " ".repeat(LABEL_WIDTH));
+            rv.push_str(&format!("{:<width$}{}\n", format!("{}:", label), value.replace('\n', &indent[..]),
+                                 width = LABEL_WIDTH)[..]);
+        };
+
+        if let Some(x) = utils::contact_display_name(component) {
+            line("Name", &x[..], vec![]);
+        };
+
+        if let Some(n) = component.get_all("N").first() {
+            // N is family;given;additional;prefixes;suffixes
+            let parts = utils::split_raw_value(&n.raw_value[..], ';');
+            let labels = ["Family name", "Given name", "Middle name", "Prefix", "Suffix"];
+            for (label, part) in labels.iter().zip(parts.iter()) {
+                if !part.trim().is_empty() {
+                    line(label, &part[..], vec![]);
+                };
+            };
+        };
+
+        for prop in component.get_all("EMAIL").iter() {
+            line("Email", &prop.value_as_string()[..], property_types(prop));
+        };
+        for prop in component.get_all("TEL").iter() {
+            line("Phone", &prop.value_as_string()[..], property_types(prop));
+        };
+        for prop in component.get_all("ADR").iter() {
+            // ADR is PO box;extended address;street;locality;region;postal code;country
+            let parts: Vec<String> = utils::split_raw_value(&prop.raw_value[..], ';').into_iter()
+                .map(|x| x.trim().to_owned())
+                .collect();
+            let part = |i: usize| parts.get(i).map(|x| &x[..]).unwrap_or("");
+            let city = format!("{} {}", part(5), part(3));
+            let address: Vec<&str> = vec![part(2), part(1), part(0), city.trim(), part(4), part(6)].into_iter()
+                .filter(|x| !x.is_empty())
+                .collect();
+            line("Address", &address.join(", ")[..], property_types(prop));
+        };
+        for prop in component.get_all("ORG").iter() {
+            for value in utils::property_values(prop) {
+                line("Organization", &value[..], vec![]);
+            };
+        };
+        for prop in component.get_all("BDAY").iter() {
+            line("Birthday", &prop.value_as_string()[..], vec![]);
+        };
+        for prop in component.get_all("NOTE").iter() {
+            line("Note", &prop.value_as_string()[..], vec![]);
+        };
+        for prop in component.get_all("URL").iter() {
+            line("URL", &prop.value_as_string()[..], property_types(prop));
+        };
+    }
+    rv
+}
+
+/// The lowercased values of the TYPE parameter of a property, e.g. `work` or `cell`.
+fn property_types(prop: &Property) -> Vec<String> {
+    prop.params.iter()
+        .filter(|&(key, _)| key.eq_ignore_ascii_case("TYPE"))
+        .flat_map(|(_, value)| value.split(',').map(|x| x.trim().trim_matches('"').to_lowercase()))
+        .filter(|x| !x.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use vobject::parse_component;
+
+    use super::*;
+
+    #[test]
+    fn typed_and_multi_valued_properties() {
+        let component = parse_component(
+            "BEGIN:VCARD\r\n\
+             VERSION:3.0\r\n\
+             FN:Anna Müller\r\n\
+             N:Müller;Anna;;Dr.;\r\n\
+             EMAIL;TYPE=WORK,pref:anna@example.com\r\n\
+             EMAIL:anna@private.example\r\n\
+             TEL;TYPE=cell:+49 170 555\r\n\
+             TEL;TYPE=home,voice:030 123\r\n\
+             ADR;TYPE=work:;;Main St. 1;Berlin;;10115;Germany\r\n\
+             ORG:ACME\\, Inc.;Research\r\n\
+             NOTE:First line\\nsecond line\r\n\
+             END:VCARD\r\n"
+        ).unwrap();
+        let formatted = format_contact(&component);
+        let lines: Vec<&str> = formatted.lines().collect();
+        assert_eq!(lines, vec![
+            "Name:         Anna Müller",
+            "Family name:  Müller",
+            "Given name:   Anna",
+            "Prefix:       Dr.",
+            "Email:        anna@example.com (work, pref)",
+            "Email:        anna@private.example",
+            "Phone:        +49 170 555 (cell)",
+            "Phone:        030 123 (home, voice)",
+            "Address:      Main St. 1, 10115 Berlin, Germany (work)",
+            "Organization: ACME, Inc.; Research",
+            "Note:         First line",
+            "              second line",
+        ]);
+    }
+}
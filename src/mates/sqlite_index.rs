@@ -0,0 +1,352 @@
+//! The index stored in an SQLite database, selected with `MATES_INDEX_BACKEND=sqlite`. Unlike the
+//! text index, there is one row per contact, with email addresses, phone numbers and extra field
+//! values in separate tables. `contacts_fts` is a full-text search table over all of them, for use
+//! with other tools; mates itself searches the items returned by `load`, like for the text index.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path;
+
+use rusqlite::{Connection,OpenFlags,NO_PARAMS};
+
+use utils::{FileStamp,IndexBackend,IndexHeader,IndexItem,incompatible_index_error};
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS contacts (
+        id INTEGER PRIMARY KEY,
+        path TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        collection TEXT NOT NULL,
+        uid TEXT NOT NULL,
+        mtime_secs INTEGER,
+        mtime_nanos INTEGER,
+        size INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS contacts_uid ON contacts(uid);
+    CREATE TABLE IF NOT EXISTS emails (
+        contact_id INTEGER NOT NULL REFERENCES contacts(id),
+        position INTEGER NOT NULL,
+        email TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS emails_email ON emails(email);
+    CREATE TABLE IF NOT EXISTS phones (
+        contact_id INTEGER NOT NULL REFERENCES contacts(id),
+        position INTEGER NOT NULL,
+        number TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS phones_number ON phones(number);
+    CREATE TABLE IF NOT EXISTS fields (
+        contact_id INTEGER NOT NULL REFERENCES contacts(id),
+        field TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE VIRTUAL TABLE IF NOT EXISTS contacts_fts USING fts5(name, emails, phones, fields);
+";
+
+pub struct SqliteIndex {
+    path: path::PathBuf
+}
+
+impl SqliteIndex {
+    pub fn new(path: &path::Path) -> SqliteIndex {
+        SqliteIndex { path: path.to_owned() }
+    }
+}
+
+impl IndexBackend for SqliteIndex {
+    fn load(&self) -> io::Result<(IndexHeader, Vec<IndexItem>)> {
+        // Like for the text index, a missing index is an error and not an empty one.
+        try!(fs::metadata(&self.path));
+        let conn = try!(Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                        .map_err(sql_error));
+
+        let header_line: Option<String> = match conn.query_row(
+            "SELECT value FROM meta WHERE key = 'header'", NO_PARAMS, |row| row.get(0)) {
+            Ok(x) => Some(x),
+            Err(_) => None
+        };
+        let header = match header_line {
+            Some(x) => try!(IndexHeader::parse(&x[..])),
+            None => return Err(incompatible_index_error())
+        };
+
+        let emails = try!(read_values(&conn, "SELECT contact_id, email FROM emails ORDER BY contact_id, position"));
+        let phones = try!(read_values(&conn, "SELECT contact_id, number FROM phones ORDER BY contact_id, position"));
+
+        let mut field_values: HashMap<(i64, String), Vec<String>> = HashMap::new();
+        {
+            let mut stmt = try!(conn.prepare(
+                "SELECT contact_id, field, value FROM fields ORDER BY contact_id, position"
+            ).map_err(sql_error));
+            let rows = try!(stmt.query_map(NO_PARAMS, |row| {
+                Ok((try!(row.get::<_, i64>(0)), try!(row.get::<_, String>(1)), try!(row.get::<_, String>(2))))
+            }).map_err(sql_error));
+            for row in rows {
+                let (id, field, value) = try!(row.map_err(sql_error));
+                field_values.entry((id, field)).or_insert_with(Vec::new).push(value);
+            };
+        }
+
+        let mut stmt = try!(conn.prepare(
+            "SELECT id, path, name, collection, uid, mtime_secs, mtime_nanos, size FROM contacts ORDER BY id"
+        ).map_err(sql_error));
+        let rows = try!(stmt.query_map(NO_PARAMS, |row| {
+            Ok((
+                try!(row.get::<_, i64>(0)),
+                try!(row.get::<_, String>(1)),
+                try!(row.get::<_, String>(2)),
+                try!(row.get::<_, String>(3)),
+                try!(row.get::<_, String>(4)),
+                try!(row.get::<_, Option<i64>>(5)),
+                try!(row.get::<_, Option<i64>>(6)),
+                try!(row.get::<_, Option<i64>>(7))
+            ))
+        }).map_err(sql_error));
+
+        let no_values = vec![];
+        let mut items = vec![];
+        for row in rows {
+            let (id, filepath, name, collection, uid, mtime_secs, mtime_nanos, size) = try!(row.map_err(sql_error));
+            let stamp = match (mtime_secs, mtime_nanos, size) {
+                (Some(mtime_secs), Some(mtime_nanos), Some(size)) => Some(FileStamp {
+                    mtime_secs: mtime_secs as u64,
+                    mtime_nanos: mtime_nanos as u32,
+                    size: size as u64
+                }),
+                _ => None
+            };
+            let fields: Vec<(String, Vec<String>)> = header.fields.iter()
+                .map(|field| {
+                    let values = field_values.get(&(id, field.clone())).unwrap_or(&no_values);
+                    (field.clone(), values.clone())
+                })
+                .collect();
+            let tels = phones.get(&id).unwrap_or(&no_values);
+
+            // One item per email address, like `index_items_from_contact` returns them.
+            let mut contact_emails = emails.get(&id).unwrap_or(&no_values).clone();
+            if contact_emails.is_empty() {
+                contact_emails.push(String::new());
+            };
+            for email in contact_emails.into_iter() {
+                items.push(IndexItem {
+                    email: email,
+                    name: name.clone(),
+                    filepath: Some(path::PathBuf::from(&filepath)),
+                    stamp: stamp,
+                    collection: collection.clone(),
+                    uid: uid.clone(),
+                    tels: tels.clone(),
+                    fields: fields.clone()
+                });
+            };
+        };
+        Ok((header, items))
+    }
+
+    fn save(&self, header: &IndexHeader, items: &[IndexItem]) -> io::Result<()> {
+        // The database is built from scratch next to the index and then moved over it, so readers
+        // never see a half-written index, and it doesn't matter what the file contained before,
+        // e.g. a text index after switching backends.
+        let mut tmp_name = self.path.file_name().map(|x| x.to_owned()).unwrap_or_default();
+        tmp_name.push(".tmp");
+        let tmp_path = self.path.with_file_name(tmp_name);
+        if let Err(e) = fs::remove_file(&tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            };
+        };
+
+        try!(write_database(&tmp_path, header, items));
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+/// Create a database at `path` containing the index.
+fn write_database(path: &path::Path, header: &IndexHeader, items: &[IndexItem]) -> io::Result<()> {
+    let mut conn = try!(Connection::open(path).map_err(sql_error));
+    try!(conn.execute_batch(SCHEMA).map_err(sql_error));
+
+    let tx = try!(conn.transaction().map_err(sql_error));
+    try!(tx.execute(
+        "INSERT INTO meta (key, value) VALUES ('header', ?)",
+        &[header.to_line().trim_right()]
+    ).map_err(sql_error));
+
+    // Only the first item of each file creates a contact, the others just add their email
+    // address.
+    let mut ids: HashMap<path::PathBuf, i64> = HashMap::new();
+    let mut email_counts: HashMap<i64, i64> = HashMap::new();
+
+    for item in items.iter() {
+        let filepath = match item.filepath {
+            Some(ref x) => x,
+            None => continue
+        };
+
+        let id = match ids.get(filepath) {
+            Some(x) => *x,
+            None => {
+                try!(tx.execute(
+                    "INSERT INTO contacts (path, name, collection, uid, mtime_secs, mtime_nanos, size)
+                     VALUES (?, ?, ?, ?, ?, ?, ?)",
+                    params![
+                        filepath.display().to_string(),
+                        item.name,
+                        item.collection,
+                        item.uid,
+                        item.stamp.map(|x| x.mtime_secs as i64),
+                        item.stamp.map(|x| x.mtime_nanos as i64),
+                        item.stamp.map(|x| x.size as i64)
+                    ]
+                ).map_err(sql_error));
+                let id = tx.last_insert_rowid();
+                ids.insert(filepath.clone(), id);
+
+                for (position, number) in item.tels.iter().enumerate() {
+                    try!(tx.execute(
+                        "INSERT INTO phones (contact_id, position, number) VALUES (?, ?, ?)",
+                        params![id, position as i64, number]
+                    ).map_err(sql_error));
+                };
+                for &(ref field, ref values) in item.fields.iter() {
+                    for (position, value) in values.iter().enumerate() {
+                        try!(tx.execute(
+                            "INSERT INTO fields (contact_id, field, position, value) VALUES (?, ?, ?, ?)",
+                            params![id, field, position as i64, value]
+                        ).map_err(sql_error));
+                    };
+                };
+                id
+            }
+        };
+
+        if !item.email.is_empty() {
+            let position = email_counts.entry(id).or_insert(0);
+            try!(tx.execute(
+                "INSERT INTO emails (contact_id, position, email) VALUES (?, ?, ?)",
+                params![id, *position, item.email]
+            ).map_err(sql_error));
+            *position += 1;
+        };
+    };
+
+    try!(tx.execute_batch("
+        INSERT INTO contacts_fts (rowid, name, emails, phones, fields)
+        SELECT id, name,
+            (SELECT group_concat(email, ' ') FROM emails WHERE contact_id = contacts.id),
+            (SELECT group_concat(number, ' ') FROM phones WHERE contact_id = contacts.id),
+            (SELECT group_concat(value, ' ') FROM fields WHERE contact_id = contacts.id)
+        FROM contacts;
+    ").map_err(sql_error));
+
+    tx.commit().map_err(sql_error)
+}
+
+/// Read a query returning a contact id and a value per row into a map from ids to values.
+fn read_values(conn: &Connection, sql: &str) -> io::Result<HashMap<i64, Vec<String>>> {
+    let mut rv: HashMap<i64, Vec<String>> = HashMap::new();
+    let mut stmt = try!(conn.prepare(sql).map_err(sql_error));
+    let rows = try!(stmt.query_map(NO_PARAMS, |row| {
+        Ok((try!(row.get::<_, i64>(0)), try!(row.get::<_, String>(1))))
+    }).map_err(sql_error));
+    for row in rows {
+        let (id, value) = try!(row.map_err(sql_error));
+        rv.entry(id).or_insert_with(Vec::new).push(value);
+    };
+    Ok(rv)
+}
+
+fn sql_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Error while accessing the SQLite index: {}", e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path;
+    use std::process;
+
+    use utils::{FileStamp,IndexBackend,IndexHeader,IndexItem,TextIndex};
+    use super::*;
+
+    fn temp_path(name: &str) -> path::PathBuf {
+        env::temp_dir().join(format!("mates-test-{}-{}", name, process::id()))
+    }
+
+    fn header() -> IndexHeader {
+        IndexHeader::new(&["ORG".to_owned(), "NICKNAME".to_owned()])
+    }
+
+    /// Two items of a contact with two email addresses, and one of a contact without any.
+    fn items() -> Vec<IndexItem> {
+        let anna = IndexItem {
+            email: "anna@example.com".to_owned(),
+            name: "Anna \"Tab\tNewline\n\" Müller".to_owned(),
+            filepath: Some(path::PathBuf::from("/contacts/work/anna.vcf")),
+            stamp: Some(FileStamp { mtime_secs: 1500000000, mtime_nanos: 42, size: 123 }),
+            collection: "work".to_owned(),
+            uid: "anna\\uid".to_owned(),
+            tels: vec!["+4930123".to_owned(), "0800".to_owned()],
+            fields: vec![
+                ("ORG".to_owned(), vec!["ACME, Inc.".to_owned(), "back\\slash".to_owned()]),
+                ("NICKNAME".to_owned(), vec![])
+            ]
+        };
+        let mut anna_private = anna.clone();
+        anna_private.email = "anna@private.example".to_owned();
+        let bob = IndexItem {
+            email: String::new(),
+            name: "Bob".to_owned(),
+            filepath: Some(path::PathBuf::from("/contacts/work/bob.vcf")),
+            stamp: None,
+            collection: "work".to_owned(),
+            uid: String::new(),
+            tels: vec![],
+            fields: vec![
+                ("ORG".to_owned(), vec![]),
+                ("NICKNAME".to_owned(), vec!["Bobby".to_owned()])
+            ]
+        };
+        vec![anna, anna_private, bob]
+    }
+
+    fn save_and_load(index: &IndexBackend, path: &path::Path) -> (IndexHeader, Vec<IndexItem>) {
+        index.save(&header(), &items()).unwrap();
+        let rv = index.load().unwrap();
+        fs::remove_file(path).unwrap();
+        rv
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let path = temp_path("sqlite-round-trip");
+        let (loaded_header, loaded_items) = save_and_load(&SqliteIndex::new(&path), &path);
+        assert_eq!(loaded_header, header());
+        assert_eq!(loaded_items, items());
+    }
+
+    #[test]
+    fn same_items_as_text_index() {
+        let sqlite_path = temp_path("sqlite-same-items");
+        let text_path = temp_path("text-same-items");
+        let from_sqlite = save_and_load(&SqliteIndex::new(&sqlite_path), &sqlite_path);
+        let from_text = save_and_load(&TextIndex::new(&text_path), &text_path);
+        assert_eq!(from_sqlite, from_text);
+    }
+
+    #[test]
+    fn replace_text_index() {
+        let path = temp_path("sqlite-replace-text");
+        TextIndex::new(&path).save(&header(), &items()).unwrap();
+        let (_, loaded_items) = save_and_load(&SqliteIndex::new(&path), &path);
+        assert_eq!(loaded_items, items());
+    }
+}
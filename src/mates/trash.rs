@@ -0,0 +1,169 @@
+//! Contacts removed with `mates rm`. The trash is a directory next to the index
+//! (`$MATES_INDEX.trash`), outside of `MATES_DIR`, so vdirsyncer never sees it. Every directory in
+//! `MATES_DIR` has its own subdirectory there, named after its absolute path. Inside, contacts keep
+//! their path relative to the directory in `MATES_DIR`, which is where they are restored to.
+
+use std::fs;
+use std::io;
+use std::path;
+
+use cli::Configuration;
+use lock::Lock;
+use utils;
+use utils::CustomPathExt;
+
+/// A contact in the trash.
+pub struct TrashedContact {
+    /// Where the contact is now.
+    pub path: path::PathBuf,
+    /// Where it will be restored to.
+    pub original_path: path::PathBuf
+}
+
+/// The trash directory of a directory in `MATES_DIR`. Its name is the absolute path of `vdir`
+/// with `%` and path separators percent-encoded, so different directories never share one.
+fn trash_dir(config: &Configuration, vdir: &path::Path) -> path::PathBuf {
+    let vdir = fs::canonicalize(vdir).unwrap_or_else(|_| vdir.to_owned());
+    let mut name = String::new();
+    for c in vdir.to_string_lossy().chars() {
+        match c {
+            '%' => name.push_str("%25"),
+            '/' => name.push_str("%2F"),
+            '\\' => name.push_str("%5C"),
+            ':' => name.push_str("%3A"),
+            c => name.push(c)
+        };
+    };
+    config.trash_path.join(name)
+}
+
+/// The directory in `MATES_DIR` the contact is in, and its path relative to it.
+fn find_vdir(config: &Configuration, contact_path: &path::Path) -> io::Result<(path::PathBuf, path::PathBuf)> {
+    let contact_path = try!(fs::canonicalize(contact_path));
+    for vdir in config.vdir_paths.iter() {
+        let vdir = try!(fs::canonicalize(vdir));
+        if let Ok(relative) = contact_path.strip_prefix(&vdir) {
+            return Ok((vdir.clone(), relative.to_owned()));
+        };
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not in MATES_DIR.", contact_path.display())
+    ))
+}
+
+/// A path like `path` that doesn't exist yet, made unique by appending a number to the file stem.
+fn unused_path(path: &path::Path) -> path::PathBuf {
+    let stem = path.file_stem().and_then(|x| x.to_str()).unwrap_or("").to_owned();
+    let mut rv = path.to_owned();
+    let mut i = 1;
+    while rv.exists() {
+        rv = path.with_file_name(format!("{}-{}.vcf", stem, i));
+        i += 1;
+    };
+    rv
+}
+
+/// Rename `from` to `to`, or copy and remove it if that fails because the trash is on another
+/// filesystem than `MATES_DIR`.
+fn move_file(from: &path::Path, to: &path::Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            if fs::copy(from, to).is_err() {
+                return Err(e);
+            };
+            fs::remove_file(from)
+        }
+    }
+}
+
+/// Move a contact to the trash and remove it from the index. Returns its new path.
+pub fn move_to_trash(config: &Configuration, contact_path: &path::Path) -> io::Result<path::PathBuf> {
+    let (vdir, relative) = try!(find_vdir(config, contact_path));
+    let trash_path = {
+        let _lock = try!(Lock::acquire(config));
+        let trash_path = unused_path(&trash_dir(config, &vdir).join(&relative));
+        if let Some(parent) = trash_path.parent() {
+            try!(fs::create_dir_all(parent));
+        };
+        try!(move_file(contact_path, &trash_path));
+        trash_path
+    };
+    try!(utils::update_index_for_file(config, contact_path));
+    Ok(trash_path)
+}
+
+/// All contacts in the trash, sorted by path.
+pub fn list_trash(config: &Configuration) -> io::Result<Vec<TrashedContact>> {
+    let mut rv = vec![];
+    for vdir in config.vdir_paths.iter() {
+        let vdir = try!(fs::canonicalize(vdir));
+        let trash = trash_dir(config, &vdir);
+        if !trash.is_dir() {
+            continue;
+        };
+
+        // Contacts are at most one directory deep, like collections.
+        let mut dirs = vec![trash.clone()];
+        for entry in try!(fs::read_dir(&trash)) {
+            let entry_path = try!(entry).path();
+            if entry_path.is_dir() {
+                dirs.push(entry_path);
+            };
+        };
+
+        for dir in dirs {
+            for entry in try!(fs::read_dir(&dir)) {
+                let entry_path = try!(entry).path();
+                if entry_path.str_extension().unwrap_or("") != "vcf" || !entry_path.is_file() {
+                    continue;
+                };
+                let original_path = match entry_path.strip_prefix(&trash) {
+                    Ok(x) => vdir.join(x),
+                    Err(_) => continue
+                };
+                rv.push(TrashedContact { path: entry_path, original_path: original_path });
+            };
+        };
+    };
+    rv.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(rv)
+}
+
+/// Move a contact from the trash back to where it was, and add it to the index again. `name` is
+/// its path as returned by `list_trash`, or just its filename. Returns the restored path.
+pub fn restore_from_trash(config: &Configuration, name: &str) -> io::Result<path::PathBuf> {
+    let name_path = path::Path::new(name);
+    let mut matches: Vec<TrashedContact> = try!(list_trash(config)).into_iter()
+        .filter(|x| x.path == name_path || x.path.file_name() == Some(name_path.as_os_str()))
+        .collect();
+
+    let contact = match matches.len() {
+        0 => return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "No such contact in the trash, see `mates trash list`."
+        )),
+        1 => matches.remove(0),
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Several contacts in the trash have this name, give the whole path."
+        ))
+    };
+
+    {
+        let _lock = try!(Lock::acquire(config));
+        if contact.original_path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists.", contact.original_path.display())
+            ));
+        };
+        if let Some(parent) = contact.original_path.parent() {
+            try!(fs::create_dir_all(parent));
+        };
+        try!(move_file(&contact.path, &contact.original_path));
+    }
+    try!(utils::update_index_for_file(config, &contact.original_path));
+    Ok(contact.original_path)
+}
@@ -0,0 +1,166 @@
+//! Tracking of how often and how recently email addresses were used, to rank query results. The
+//! state file lives next to the index and has one line per address:
+//!
+//! ```text
+//! email\tcount\tlast use as unix timestamp
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead,Write};
+use std::io;
+use std::path;
+use std::time;
+
+use atomicwrites::{AtomicFile,AllowOverwrite};
+
+/// After this many seconds without a use, the weight of an address is halved.
+const HALF_LIFE: f64 = 30.0 * 24.0 * 60.0 * 60.0;
+
+pub struct UsageEntry {
+    pub count: u64,
+    pub last_used: u64
+}
+
+pub struct UsageStats {
+    entries: HashMap<String, UsageEntry>
+}
+
+impl UsageStats {
+    /// Read the state file. A missing file means nothing was used yet, invalid lines are skipped.
+    pub fn load(usage_path: &path::Path) -> io::Result<UsageStats> {
+        let mut entries = HashMap::new();
+        let usage_file = match fs::File::open(usage_path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(UsageStats { entries: entries }),
+            Err(e) => return Err(e)
+        };
+
+        for line in io::BufReader::new(usage_file).lines() {
+            let line = try!(line);
+            let mut parts = line.split('\t');
+            let entry = match (parts.next(), parts.next(), parts.next()) {
+                (Some(email), Some(count), Some(last_used)) => match (count.parse(), last_used.parse()) {
+                    (Ok(count), Ok(last_used)) => (email.to_owned(), UsageEntry { count: count, last_used: last_used }),
+                    _ => continue
+                },
+                _ => continue
+            };
+            entries.insert(entry.0, entry.1);
+        };
+        Ok(UsageStats { entries: entries })
+    }
+
+    pub fn save(&self, usage_path: &path::Path) -> io::Result<()> {
+        let mut rv = String::new();
+        for (email, entry) in self.entries.iter() {
+            rv.push_str(&format!("{}\t{}\t{}\n", email, entry.count, entry.last_used)[..]);
+        };
+
+        let af = AtomicFile::new(usage_path, AllowOverwrite);
+        try!(af.write(|f| {
+            f.write_all(rv.as_bytes())
+        }));
+        Ok(())
+    }
+
+    pub fn record(&mut self, email: &str, now: u64) {
+        let key = normalize_email(email);
+        if key.is_empty() {
+            return;
+        };
+        let entry = self.entries.entry(key).or_insert(UsageEntry { count: 0, last_used: now });
+        entry.count += 1;
+        entry.last_used = now;
+    }
+
+    /// How often the address was used, halved for every `HALF_LIFE` since it was last used. When
+    /// each of the uses happened is not recorded.
+    pub fn frecency(&self, email: &str, now: u64) -> f64 {
+        match self.entries.get(&normalize_email(email)) {
+            Some(entry) => {
+                let age = now.saturating_sub(entry.last_used) as f64;
+                entry.count as f64 * (0.5f64).powf(age / HALF_LIFE)
+            },
+            None => 0.0
+        }
+    }
+}
+
+/// Addresses are compared ignoring case and surrounding whitespace.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// The current time as unix timestamp.
+pub fn now() -> u64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(x) => x.as_secs(),
+        Err(_) => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn empty() -> UsageStats {
+        UsageStats { entries: HashMap::new() }
+    }
+
+    #[test]
+    fn record() {
+        let mut stats = empty();
+        stats.record("Anna@Example.com ", 100);
+        stats.record("anna@example.com", 200);
+        stats.record("  ", 200);
+        assert_eq!(stats.entries.len(), 1);
+        let entry = &stats.entries["anna@example.com"];
+        assert_eq!(entry.count, 2);
+        assert_eq!(entry.last_used, 200);
+    }
+
+    #[test]
+    fn frecency_order() {
+        let now = 1000 * DAY;
+        let mut stats = empty();
+        stats.record("often@example.com", now - DAY);
+        stats.record("often@example.com", now - DAY);
+        stats.record("once@example.com", now - DAY);
+        stats.record("long-ago@example.com", now - 200 * DAY);
+        stats.record("long-ago@example.com", now - 200 * DAY);
+
+        let frecency = |x| stats.frecency(x, now);
+        assert!(frecency("often@example.com") > frecency("once@example.com"));
+        assert!(frecency("once@example.com") > frecency("long-ago@example.com"));
+        assert!(frecency("long-ago@example.com") > frecency("never@example.com"));
+        assert_eq!(frecency("never@example.com"), 0.0);
+        assert_eq!(frecency("ONCE@example.com"), frecency("once@example.com"));
+        // One half-life later, the weight is halved.
+        assert!((stats.frecency("often@example.com", now - DAY + HALF_LIFE as u64) - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn load_save_round_trip() {
+        let path = env::temp_dir().join(format!("mates-test-usage-{}", process::id()));
+        assert_eq!(UsageStats::load(&path).unwrap().entries.len(), 0);
+
+        let mut stats = empty();
+        stats.record("anna@example.com", 100);
+        stats.record("anna@example.com", 300);
+        stats.record("bob@example.com", 200);
+        stats.save(&path).unwrap();
+        let loaded = UsageStats::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.entries.len(), 2);
+        assert_eq!((loaded.entries["anna@example.com"].count, loaded.entries["anna@example.com"].last_used), (2, 300));
+        assert_eq!((loaded.entries["bob@example.com"].count, loaded.entries["bob@example.com"].last_used), (1, 200));
+    }
+}
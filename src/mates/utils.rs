@@ -1,18 +1,29 @@
 use std::borrow::ToOwned;
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::{BTreeMap,BTreeSet,HashMap,HashSet};
+use std::fmt;
 use std::fs;
-use std::io::{Read,Write};
+use std::io::{BufRead,Read,Write};
 use std::io;
 use std::path;
 use std::process;
+use std::time;
 use std::convert::AsRef;
 
-use atomicwrites::{AtomicFile,DisallowOverwrite};
+use atomicwrites::{AtomicFile,AllowOverwrite,DisallowOverwrite};
 use email::rfc5322::Rfc5322Parser;
+use rayon::prelude::*;
 use uuid::Uuid;
-use vobject::{Component,Property,parse_component,write_component};
+use vobject::{Component,Property,parse_component,unescape_chars,write_component};
 
-use cli::Configuration;
+use cli::{Configuration,IndexBackendKind,StaleIndexAction};
+use lock::Lock;
+#[cfg(feature = "sqlite")]
+use sqlite_index::SqliteIndex;
+use matcher;
+use query::Query;
+use usage;
+use usage::UsageStats;
 
 pub trait CustomPathExt {
     fn metadata(&self) -> io::Result<fs::Metadata>;
@@ -51,15 +62,94 @@ pub fn handle_process(process: &mut process::Child) -> io::Result<()> {
 }
 
 
+/// Version of the index format written by this version of mates. Has to be increased whenever the
+/// meaning of the index columns changes.
+const INDEX_VERSION: u32 = 5;
+
+/// The first line of an index file starts with this, followed by `INDEX_VERSION` and the
+/// comma-separated list of extra fields. Since it contains no tabs, searches that match it get an
+/// item without name and path, which is ignored by all commands.
+const INDEX_HEADER_PREFIX: &'static str = "# mates index v";
+
+/// How the lines of an index file are to be read.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum IndexFormat {
+    /// Written by old versions of mates without a header. Fields are not escaped.
+    Legacy,
+    /// Written by this version of mates, see `INDEX_VERSION`.
+    Current
+}
+
+/// The first line of an index file.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct IndexHeader {
+    pub format: IndexFormat,
+    /// The vCard properties stored in the columns after the file stamp, see `MATES_INDEX_FIELDS`.
+    pub fields: Vec<String>
+}
+
+impl IndexHeader {
+    pub fn new(fields: &[String]) -> IndexHeader {
+        IndexHeader {
+            format: IndexFormat::Current,
+            fields: fields.to_vec()
+        }
+    }
+
+    pub fn parse(line: &str) -> io::Result<IndexHeader> {
+        if !line.starts_with(INDEX_HEADER_PREFIX) {
+            return Ok(IndexHeader { format: IndexFormat::Legacy, fields: vec![] });
+        };
+
+        let mut parts = line[INDEX_HEADER_PREFIX.len()..].split_whitespace();
+        match parts.next().map(|x| x.parse::<u32>()) {
+            Some(Ok(INDEX_VERSION)) => (),
+            _ => return Err(incompatible_index_error())
+        };
+
+        let fields = match parts.next() {
+            Some(x) => x.split(',').map(|x| x.to_owned()).collect(),
+            None => vec![]
+        };
+        Ok(IndexHeader::new(&fields[..]))
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut rv = format!("{}{}", INDEX_HEADER_PREFIX, INDEX_VERSION);
+        if !self.fields.is_empty() {
+            rv.push(' ');
+            rv.push_str(&self.fields.join(",")[..]);
+        };
+        rv.push('\n');
+        rv
+    }
+}
+
+pub fn incompatible_index_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "The index was written by an incompatible version of mates, run `mates index --full`."
+    )
+}
+
 pub struct IndexIterator {
-    linebuffer: Vec<String>
+    itembuffer: Vec<IndexItem>
 }
 
 impl IndexIterator {
-    fn new(output: &String) -> IndexIterator {
-        let rv = output.split('\n').map(|x| x.to_string()).collect();
+    fn new(output: &String, header: &IndexHeader) -> IndexIterator {
+        let rv = output.split('\n')
+            .filter(|x| !x.starts_with(INDEX_HEADER_PREFIX))
+            .map(|x| IndexItem::new(x, header))
+            .collect();
+        IndexIterator::from_items(rv)
+    }
+
+    fn from_items(mut items: Vec<IndexItem>) -> IndexIterator {
+        // Items are popped from the back, but should be returned in their original order.
+        items.reverse();
         IndexIterator {
-            linebuffer: rv
+            itembuffer: items
         }
     }
 }
@@ -68,32 +158,325 @@ impl Iterator for IndexIterator {
     type Item = IndexItem;
 
     fn next(&mut self) -> Option<IndexItem> {
-        match self.linebuffer.pop() {
-            Some(x) => Some(IndexItem::new(x)),
-            None => None
-        }
+        self.itembuffer.pop()
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct IndexItem {
     pub email: String,
     pub name: String,
-    pub filepath: Option<path::PathBuf>
+    pub filepath: Option<path::PathBuf>,
+    pub stamp: Option<FileStamp>,
+    /// Name of the collection the contact file is in, see `find_collections`.
+    pub collection: String,
+    /// The UID of the contact, empty if it has none.
+    pub uid: String,
+    /// Phone numbers of the contact as returned by `strip_tel`. The default country prefix is only
+    /// applied when searching, so changing it doesn't require rebuilding the index.
+    pub tels: Vec<String>,
+    /// Values of the extra fields listed in the index header, in the same order.
+    pub fields: Vec<(String, Vec<String>)>
 }
 
 impl IndexItem {
-    fn new(line: String) -> IndexItem {
+    pub fn new(line: &str, header: &IndexHeader) -> IndexItem {
         let mut parts = line.split('\t');
+        let mut next_part = || match (parts.next(), header.format) {
+            (Some(x), IndexFormat::Legacy) => Some(x.to_owned()),
+            (Some(x), IndexFormat::Current) => Some(unescape_index_field(x)),
+            (None, _) => None
+        };
+
+        let email = next_part().unwrap_or_else(String::new);
+        let name = next_part().unwrap_or_else(String::new);
+        let filepath = next_part().map(path::PathBuf::from);
+        let stamp = match (next_part(), next_part()) {
+            (Some(mtime), Some(size)) => FileStamp::parse(&mtime[..], &size[..]),
+            _ => None
+        };
+        let collection = next_part().unwrap_or_else(String::new);
+        let uid = next_part().unwrap_or_else(String::new);
+        let tels = match parts.next() {
+            Some(x) => split_index_values(x),
+            None => vec![]
+        };
+        let fields = header.fields.iter()
+            .map(|field| (field.clone(), match parts.next() {
+                Some(x) => split_index_values(x),
+                None => vec![]
+            }))
+            .collect();
 
         IndexItem {
-            email: parts.next().unwrap_or("").to_string(),
-            name: parts.next().unwrap_or("").to_string(),
-            filepath: match parts.next() {
-                Some(x) => Some(path::PathBuf::from(x)),
-                None => None
-            }
+            email: email,
+            name: name,
+            filepath: filepath,
+            stamp: stamp,
+            collection: collection,
+            uid: uid,
+            tels: tels,
+            fields: fields
         }
     }
+
+    /// Serialize the item as a line (including the trailing newline) in the current index format.
+    pub fn to_line(&self) -> String {
+        let mut rv = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            escape_index_field(&self.email[..]),
+            escape_index_field(&self.name[..]),
+            match self.filepath {
+                Some(ref x) => escape_index_field(&x.display().to_string()[..]),
+                None => String::new()
+            },
+            match self.stamp {
+                Some(ref x) => x.to_string(),
+                None => "\t".to_owned()
+            },
+            escape_index_field(&self.collection[..]),
+            escape_index_field(&self.uid[..])
+        );
+        rv.push('\t');
+        rv.push_str(&join_index_values(&self.tels[..])[..]);
+        for &(_, ref values) in self.fields.iter() {
+            rv.push('\t');
+            rv.push_str(&join_index_values(&values[..])[..]);
+        };
+        rv.push('\n');
+        rv
+    }
+
+    /// Values of the given extra field, empty if the field isn't indexed.
+    pub fn field(&self, name: &str) -> &[String] {
+        for &(ref field, ref values) in self.fields.iter() {
+            if field == name {
+                return &values[..];
+            };
+        };
+        &[]
+    }
+}
+
+/// Escape backslashes, tabs and line breaks, which would otherwise break the index format.
+fn escape_index_field(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => rv.push_str("\\\\"),
+            '\t' => rv.push_str("\\t"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            x => rv.push(x)
+        };
+    };
+    rv
+}
+
+/// Reverse `escape_index_field`. Unknown escape sequences are kept as they are.
+fn unescape_index_field(s: &str) -> String {
+    let mut rv = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            rv.push(c);
+            continue;
+        };
+        match chars.next() {
+            Some('\\') => rv.push('\\'),
+            Some('t') => rv.push('\t'),
+            Some('n') => rv.push('\n'),
+            Some('r') => rv.push('\r'),
+            Some(',') => rv.push(','),
+            Some(x) => { rv.push('\\'); rv.push(x); },
+            None => rv.push('\\')
+        };
+    };
+    rv
+}
+
+/// Escape the values of an extra field column and join them with commas.
+fn join_index_values(values: &[String]) -> String {
+    let values: Vec<String> = values.iter()
+        .map(|x| escape_index_field(&x[..]).replace(",", "\\,"))
+        .collect();
+    values.join(",")
+}
+
+/// Split an extra field column at every unescaped comma and unescape the values.
+fn split_index_values(s: &str) -> Vec<String> {
+    let mut rv = vec![];
+    if s.is_empty() {
+        return rv;
+    };
+
+    let mut current = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            current.push(c);
+            if let Some(x) = chars.next() {
+                current.push(x);
+            };
+        } else if c == ',' {
+            rv.push(unescape_index_field(&current[..]));
+            current.clear();
+        } else {
+            current.push(c);
+        }
+    };
+    rv.push(unescape_index_field(&current[..]));
+    rv
+}
+
+/// Read all lines of the index file, except for the header.
+fn read_index(index_path: &path::Path) -> io::Result<(IndexHeader, Vec<String>)> {
+    let index_file = try!(fs::File::open(index_path));
+    let mut lines = io::BufReader::new(index_file).lines();
+    let mut rv = vec![];
+
+    let header = match lines.next() {
+        Some(first_line) => {
+            let first_line = try!(first_line);
+            let header = try!(IndexHeader::parse(&first_line[..]));
+            if header.format == IndexFormat::Legacy && !first_line.is_empty() {
+                rv.push(first_line);
+            };
+            header
+        },
+        None => IndexHeader::new(&[])
+    };
+
+    for line in lines {
+        let line = try!(line);
+        if !line.is_empty() {
+            rv.push(line);
+        };
+    };
+    Ok((header, rv))
+}
+
+/// Read only the header of the index file.
+fn read_index_header(index_path: &path::Path) -> io::Result<IndexHeader> {
+    let index_file = try!(fs::File::open(index_path));
+    match io::BufReader::new(index_file).lines().next() {
+        Some(first_line) => IndexHeader::parse(&try!(first_line)[..]),
+        None => Ok(IndexHeader::new(&[]))
+    }
+}
+
+/// Where the index is stored, see `MATES_INDEX_BACKEND`. All queries work on the items returned by
+/// `load`, so every backend gives the same results.
+pub trait IndexBackend {
+    /// Read the header and all items, in index order.
+    fn load(&self) -> io::Result<(IndexHeader, Vec<IndexItem>)>;
+
+    /// Replace the whole index. Items of the same file are next to each other.
+    fn save(&self, header: &IndexHeader, items: &[IndexItem]) -> io::Result<()>;
+}
+
+/// The index as tab-separated text file, one line per item.
+pub struct TextIndex {
+    path: path::PathBuf
+}
+
+impl TextIndex {
+    pub fn new(path: &path::Path) -> TextIndex {
+        TextIndex { path: path.to_owned() }
+    }
+}
+
+impl IndexBackend for TextIndex {
+    fn load(&self) -> io::Result<(IndexHeader, Vec<IndexItem>)> {
+        let (header, lines) = try!(read_index(&self.path));
+        let items = lines.into_iter()
+            .map(|line| IndexItem::new(&line[..], &header))
+            .collect();
+        Ok((header, items))
+    }
+
+    fn save(&self, header: &IndexHeader, items: &[IndexItem]) -> io::Result<()> {
+        let af = AtomicFile::new(&self.path, AllowOverwrite);
+        try!(af.write::<(), io::Error, _>(|outf| {
+            try!(outf.write_all(header.to_line().as_bytes()));
+            for item in items.iter() {
+                try!(outf.write_all(item.to_line().as_bytes()));
+            };
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+/// The backend selected with `MATES_INDEX_BACKEND`.
+pub fn open_index(config: &Configuration) -> io::Result<Box<IndexBackend>> {
+    match config.index_backend {
+        IndexBackendKind::Text => Ok(Box::new(TextIndex::new(&config.index_path))),
+        IndexBackendKind::Sqlite => open_sqlite_index(config)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite_index(config: &Configuration) -> io::Result<Box<IndexBackend>> {
+    Ok(Box::new(SqliteIndex::new(&config.index_path)))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite_index(_config: &Configuration) -> io::Result<Box<IndexBackend>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "mates was built without SQLite support, rebuild it with `--features sqlite`."
+    ))
+}
+
+/// Modification time and size of a contact file at the time it was indexed. Used to decide
+/// whether a file needs to be parsed again.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FileStamp {
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    pub size: u64
+}
+
+impl FileStamp {
+    pub fn from_metadata(metadata: &fs::Metadata) -> io::Result<FileStamp> {
+        let mtime = match try!(metadata.modified()).duration_since(time::UNIX_EPOCH) {
+            Ok(x) => x,
+            Err(_) => time::Duration::from_secs(0)
+        };
+        Ok(FileStamp {
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            size: metadata.len()
+        })
+    }
+
+    pub fn from_path<P: AsRef<path::Path>>(path: P) -> io::Result<FileStamp> {
+        FileStamp::from_metadata(&try!(fs::metadata(path)))
+    }
+
+    fn parse(mtime: &str, size: &str) -> Option<FileStamp> {
+        let mut mtime_parts = mtime.splitn(2, '.');
+        let mtime_secs = match mtime_parts.next().map(|x| x.parse()) {
+            Some(Ok(x)) => x,
+            _ => return None
+        };
+        let mtime_nanos = match mtime_parts.next().map(|x| x.parse()) {
+            Some(Ok(x)) => x,
+            _ => return None
+        };
+        let size = match size.parse() {
+            Ok(x) => x,
+            Err(_) => return None
+        };
+        Some(FileStamp { mtime_secs: mtime_secs, mtime_nanos: mtime_nanos, size: size })
+    }
+}
+
+impl fmt::Display for FileStamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09}\t{}", self.mtime_secs, self.mtime_nanos, self.size)
+    }
 }
 
 pub struct Contact {
@@ -146,6 +529,17 @@ impl Contact {
         }));
         Ok(())
     }
+
+    /// Like `write_create`, but overwrites an existing file.
+    pub fn write_replace(&self) -> io::Result<()> {
+        let string = write_component(&self.component);
+        let af = AtomicFile::new(&self.path, AllowOverwrite);
+
+        try!(af.write(|f| {
+            f.write_all(string.as_bytes())
+        }));
+        Ok(())
+    }
 }
 
 
@@ -168,8 +562,270 @@ fn generate_component(uid: String, fullname: Option<&str>, email: Option<&str>)
 }
 
 pub fn index_query<'a>(config: &Configuration, query: &str) -> io::Result<IndexIterator> {
+    let items = try!(matching_items(config, query, matcher::SUBSTRING)).into_iter()
+        .map(|(_, item)| item)
+        .collect();
+    Ok(IndexIterator::from_items(items))
+}
+
+/// Search the index with the matcher chosen with `--match` or `MATES_MATCH`, otherwise the one
+/// named `default_matcher`, and return the matching items of the selected collection with their
+/// scores.
+fn matching_items(config: &Configuration, query: &str, default_matcher: &str) -> io::Result<Vec<(u32, IndexItem)>> {
+    try!(check_index_freshness(config));
+    let matcher = try!(config.matcher(default_matcher));
+    Ok(try!(matcher.find(config, query)).into_iter()
+       .filter(|&(_, ref item)| in_selected_collection(config, item))
+       .collect())
+}
+
+/// Warn about or rebuild a stale index, depending on `MATES_STALE_INDEX`. Nothing is written to
+/// stdout, as that would confuse mutt.
+pub fn check_index_freshness(config: &Configuration) -> io::Result<()> {
+    if config.stale_index == StaleIndexAction::Ignore ||
+        !try!(index_is_stale(config)) {
+        return Ok(());
+    };
+
+    match config.stale_index {
+        StaleIndexAction::Warn => {
+            let _ = writeln!(&mut io::stderr(),
+                             "Warning: Contacts changed since the index was written, run `mates index`.");
+        },
+        StaleIndexAction::Rebuild => {
+            if let Err(e) = build_index(config, false) {
+                let _ = writeln!(&mut io::stderr(), "Error while updating the index: {}", e);
+            };
+        },
+        StaleIndexAction::Ignore => ()
+    };
+    Ok(())
+}
+
+/// Whether any directory in `MATES_DIR` or any collection was modified after the index file. A
+/// missing index is always stale. Only directories are checked, so this is fast for large address
+/// books and notices contacts being created, removed or renamed, which includes every change
+/// vdirsyncer makes, as it replaces files. Files changed in place are only noticed by `mates index`.
+pub fn index_is_stale(config: &Configuration) -> io::Result<bool> {
+    let index_mtime = match fs::metadata(&config.index_path) {
+        Ok(x) => try!(x.modified()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e)
+    };
+
+    // Checking the top-level directories notices new collections.
+    for dir in config.vdir_paths.iter() {
+        if try!(try!(fs::metadata(dir)).modified()) > index_mtime {
+            return Ok(true);
+        };
+    };
+
+    for collection in try!(find_collections(&config.vdir_paths[..])) {
+        if try!(try!(fs::metadata(&collection.path)).modified()) > index_mtime {
+            return Ok(true);
+        };
+    };
+    Ok(false)
+}
+
+/// A directory of contact files, like a CardDAV addressbook synchronized by vdirsyncer.
+pub struct Collection {
+    pub name: String,
+    pub path: path::PathBuf
+}
+
+fn collection_name(dir: &path::Path) -> String {
+    match dir.file_name() {
+        Some(x) => x.to_string_lossy().into_owned(),
+        None => String::new()
+    }
+}
+
+/// Find the collections in the directories from `MATES_DIR`. Every subdirectory of them is a
+/// collection, as is the directory itself if it directly contains contact files or has no
+/// subdirectories. Hidden directories are skipped.
+pub fn find_collections(dirs: &[path::PathBuf]) -> io::Result<Vec<Collection>> {
+    let mut rv = vec![];
+    for dir in dirs.iter() {
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("MATES_DIR must only contain directories, {} isn't one.", dir.display()),
+            ));
+        };
+
+        let mut has_contacts = false;
+        let mut subdirs = vec![];
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let pathbuf = entry.path();
+            // Usually known without a stat of every contact file, which matters for the freshness
+            // check on every query.
+            let is_dir = match entry.file_type() {
+                Ok(ref x) if !x.is_symlink() => x.is_dir(),
+                _ => pathbuf.is_dir()
+            };
+            if is_dir {
+                if !collection_name(&pathbuf).starts_with('.') {
+                    subdirs.push(pathbuf);
+                };
+            } else if pathbuf.str_extension().unwrap_or("") == "vcf" {
+                has_contacts = true;
+            };
+        };
+
+        if has_contacts || subdirs.is_empty() {
+            rv.push(Collection { name: collection_name(dir), path: dir.clone() });
+        };
+        subdirs.sort();
+        rv.extend(subdirs.into_iter().map(|x| Collection { name: collection_name(&x), path: x }));
+    };
+
+    // Directories with the same name, e.g. the `default` addressbook of two vdirsyncer accounts,
+    // are told apart by their parent directory, or failing that by their whole path.
+    for qualify in &[false, true] {
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for collection in rv.iter() {
+            *counts.entry(collection.name.clone()).or_insert(0) += 1;
+        };
+        for collection in rv.iter_mut() {
+            if counts[&collection.name] < 2 {
+                continue;
+            };
+            collection.name = match (*qualify, collection.path.parent()) {
+                (false, Some(parent)) => format!("{}/{}", collection_name(parent), collection_name(&collection.path)),
+                _ => collection.path.display().to_string()
+            };
+        };
+    };
+    Ok(rv)
+}
+
+/// The collection the contact file is in, see `find_collections`. If it isn't in any, one is
+/// made up from its directory.
+pub fn collection_of(config: &Configuration, contact_path: &path::Path) -> io::Result<Collection> {
+    let dir = contact_path.parent().unwrap_or(path::Path::new(""));
+    let mut normalized_dirs = HashMap::new();
+    let target = normalize_path(dir, &mut normalized_dirs);
+    for collection in try!(find_collections(&config.vdir_paths[..])) {
+        if normalize_path(&collection.path, &mut normalized_dirs) == target {
+            return Ok(collection);
+        };
+    };
+    Ok(Collection { name: collection_name(dir), path: dir.to_owned() })
+}
+
+/// The collection new contacts should be written to: The one with the given name, or the only
+/// one if no name is given.
+pub fn find_collection(config: &Configuration, name: Option<&str>) -> io::Result<Collection> {
+    let mut collections = try!(find_collections(&config.vdir_paths[..]));
+    let names: Vec<String> = collections.iter().map(|x| x.name.clone()).collect();
+
+    let i = match name {
+        Some(name) => collections.iter().position(|x| x.name == name),
+        None if collections.len() == 1 => Some(0),
+        None => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Multiple collections found, choose one of: {}", names.join(", ")),
+        ))
+    };
+
+    match i {
+        Some(i) => Ok(collections.swap_remove(i)),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No such collection, choose one of: {}", names.join(", ")),
+        ))
+    }
+}
+
+/// Whether the item belongs to the collection selected with `--collection`, if any.
+pub fn in_selected_collection(config: &Configuration, item: &IndexItem) -> bool {
+    match config.collection {
+        Some(ref x) => item.collection == *x,
+        None => true
+    }
+}
+
+/// The most that frequent and recent use of an address can add to the score of a result. Chosen
+/// so a frequently used contact matched by a word prefix beats an unused one matched by a name
+/// prefix, but not so high that a typo beats a prefix match.
+const MAX_USAGE_SCORE: f64 = 50.0;
+
+/// Like `index_query`, but sorts the results by the score the matcher gave them and, if
+/// `MATES_TRACK_USAGE` is set, how often and how recently their email address was used, best first.
+/// `default_matcher` is used unless another one is chosen, see `matching_items`.
+pub fn ranked_index_query(config: &Configuration, query: &str, default_matcher: &str) -> io::Result<Vec<IndexItem>> {
+    let scored = try!(matching_items(config, query, default_matcher));
+
+    let usage_stats = if config.track_usage {
+        Some(try!(UsageStats::load(&config.usage_path)))
+    } else {
+        None
+    };
+    let now = usage::now();
+
+    let mut rv: Vec<(f64, IndexItem)> = scored.into_iter()
+        .map(|(score, item)| {
+            let usage_score = match usage_stats {
+                Some(ref stats) => {
+                    let frecency = stats.frecency(&item.email[..], now);
+                    MAX_USAGE_SCORE * frecency / (frecency + 1.0)
+                },
+                None => 0.0
+            };
+            (score as f64 + usage_score, item)
+        })
+        .collect();
+    // Stable sort, so equally good matches stay in index order
+    rv.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(cmp::Ordering::Equal));
+    Ok(rv.into_iter().map(|(_, item)| item).collect())
+}
+
+/// Remember that the given email addresses were used, if `MATES_TRACK_USAGE` is set.
+pub fn record_usage(config: &Configuration, emails: &[&str]) -> io::Result<()> {
+    if !config.track_usage {
+        return Ok(());
+    };
+
+    let _lock = try!(Lock::acquire(config));
+    let mut stats = try!(UsageStats::load(&config.usage_path));
+    let now = usage::now();
+    for email in emails.iter() {
+        stats.record(email, now);
+    };
+    stats.save(&config.usage_path)
+}
+
+/// Parse a query and make sure every field it searches in is in the index.
+pub fn parse_query(config: &Configuration, query: &str, header: &IndexHeader) -> io::Result<Query> {
+    let mut query = try!(Query::parse(query));
+    query.set_country_prefix(config.country_prefix.as_ref().map(|x| &x[..]));
+    for field in query.fields() {
+        if let Some(index_field) = field.index_field() {
+            if !header.fields.iter().any(|x| x == index_field) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not in the index, add it to MATES_INDEX_FIELDS and run `mates index`.",
+                            index_field)
+                ));
+            };
+        };
+    };
+    Ok(query)
+}
+
+/// Search the index by running the command given in `MATES_GREP`.
+pub fn grep_index_query(config: &Configuration, grep_cmd: &str, query: &str) -> io::Result<IndexIterator> {
+    if config.index_backend != IndexBackendKind::Text {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "MATES_GREP can only be used with the text index."
+        ));
+    };
+    let header = try!(read_index_header(&config.index_path));
     let mut process = try!(
-        command_from_config(&config.grep_cmd[..])
+        try!(command_from_config(grep_cmd))
         .arg(&query[..])
         .arg(&config.index_path)
         .stdin(process::Stdio::piped())
@@ -189,7 +845,7 @@ pub fn index_query<'a>(config: &Configuration, query: &str) -> io::Result<IndexI
 
     let mut output = String::new();
     try!(stream.read_to_string(&mut output));
-    Ok(IndexIterator::new(&output))
+    Ok(IndexIterator::new(&output, &header))
 }
 
 /// Better than index_query if you're only interested in the filepath, as duplicate entries will be
@@ -202,23 +858,532 @@ pub fn file_query(config: &Configuration, query: &str) -> io::Result<HashSet<pat
     Ok(rv)
 }
 
-pub fn index_item_from_contact(contact: &Contact) -> io::Result<String> {
-    let name = match contact.component.get_only("FN") {
-        Some(name) => name.value_as_string(),
-        None => return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "No name found.",
-        ))
+/// Find the contacts with the given phone number, see `normalize_tel`. Every contact is only
+/// returned once, in index order.
+pub fn tel_query(config: &Configuration, number: &str) -> io::Result<Vec<IndexItem>> {
+    let country_prefix = config.country_prefix.as_ref().map(|x| &x[..]);
+    let number = normalize_tel(number, country_prefix);
+    if number.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Not a phone number."
+        ));
+    };
+
+    try!(check_index_freshness(config));
+    let (_, items) = try!(try!(open_index(config)).load());
+    let mut seen_paths = HashSet::new();
+
+    Ok(items.into_iter()
+       .filter(|item| {
+           in_selected_collection(config, item) &&
+               item.tels.iter().any(|x| normalize_tel(&x[..], country_prefix) == number)
+       })
+       .filter(|item| match item.filepath {
+           Some(ref x) => seen_paths.insert(x.clone()),
+           None => false
+       })
+       .collect())
+}
+
+/// Find the files of the contacts with the given UID, in index order.
+pub fn uid_query(config: &Configuration, uid: &str) -> io::Result<Vec<path::PathBuf>> {
+    try!(check_index_freshness(config));
+    let (_, items) = try!(try!(open_index(config)).load());
+    let mut rv: Vec<path::PathBuf> = vec![];
+
+    for item in items {
+        if item.uid != uid || !in_selected_collection(config, &item) {
+            continue;
+        };
+        if let Some(filepath) = item.filepath {
+            if !rv.contains(&filepath) {
+                rv.push(filepath);
+            };
+        };
+    };
+    Ok(rv)
+}
+
+/// Remove everything but digits and a leading `+` from a phone number, and write the international
+/// call prefix `00` as `+`. The `tel:` scheme and parameters like `;ext=` of vCard 4 URIs are
+/// removed as well.
+pub fn strip_tel(number: &str) -> String {
+    let mut number = number.trim();
+    if number.to_lowercase().starts_with("tel:") {
+        number = &number[4..];
+    };
+    if let Some(i) = number.find(';') {
+        number = &number[..i];
     };
 
-    let emails = contact.component.get_all("EMAIL");
     let mut rv = String::new();
-    for email in emails.iter() {
-        rv.push_str(&format!("{}\t{}\t{}\n", email.value_as_string(), name, contact.path.display())[..]);
+    for c in number.chars() {
+        if c.is_digit(10) || (c == '+' && rv.is_empty()) {
+            rv.push(c);
+        };
+    };
+
+    if rv.starts_with("00") {
+        rv = format!("+{}", &rv[2..]);
+    };
+    rv
+}
+
+/// Make phone numbers comparable: Like `strip_tel`, but if `country_prefix` (e.g. `+49`) is given,
+/// the leading `0` of national numbers is replaced with it.
+pub fn normalize_tel(number: &str, country_prefix: Option<&str>) -> String {
+    let rv = strip_tel(number);
+    match country_prefix {
+        Some(prefix) if rv.starts_with('0') => format!("{}{}", prefix, &rv[1..]),
+        _ => rv
+    }
+}
+
+/// Return the index items for the given contact, one per email address, with values for the given
+/// extra `fields`. `stamp` should be taken from the contact's file before it was read.
+pub fn index_items_from_contact(contact: &Contact, stamp: &FileStamp, collection: &str, fields: &[String])
+                                -> io::Result<Vec<IndexItem>> {
+    // A contact without any name is still indexed, so it can be found by its phone number or
+    // edited to give it one.
+    let name = contact_display_name(&contact.component)
+        .or_else(|| contact.component.get_all("TEL").iter()
+                 .map(|x| x.value_as_string().trim().to_owned())
+                 .find(|x| !x.is_empty()))
+        .or_else(|| contact.path.file_stem().map(|x| x.to_string_lossy().into_owned()))
+        .unwrap_or_else(String::new);
+
+    let mut emails: Vec<String> = contact.component.get_all("EMAIL").iter()
+        .map(|x| x.value_as_string())
+        .collect();
+    if emails.is_empty() {
+        // Still index the contact so it can be found by name, mutt-query and email-query skip
+        // lines without an email address.
+        emails.push(String::new());
+    };
+
+    let field_values: Vec<(String, Vec<String>)> = fields.iter()
+        .map(|field| {
+            let values = contact.component.get_all(&field[..]).iter()
+                .flat_map(|prop| property_values(prop).into_iter())
+                .filter(|x| !x.is_empty())
+                .collect();
+            (field.clone(), values)
+        })
+        .collect();
+
+    let uid = match contact.component.get_only("UID") {
+        Some(x) => x.value_as_string(),
+        None => String::new()
+    };
+
+    let tels: Vec<String> = contact.component.get_all("TEL").iter()
+        .map(|x| strip_tel(&x.value_as_string()[..]))
+        .filter(|x| !x.is_empty())
+        .collect();
+
+    Ok(emails.into_iter()
+       .map(|email| IndexItem {
+           email: email,
+           name: name.clone(),
+           filepath: Some(contact.path.clone()),
+           stamp: Some(*stamp),
+           collection: collection.to_owned(),
+           uid: uid.clone(),
+           tels: tels.clone(),
+           fields: field_values.clone()
+       })
+       .collect())
+}
+
+/// The name to show for a contact. This is FN if the contact has one, otherwise it is derived from
+/// N, ORG, NICKNAME or the local part of the first email address, in that order.
+pub fn contact_display_name(component: &Component) -> Option<String> {
+    fn first_value(component: &Component, prop_name: &str) -> Option<String> {
+        component.get_all(prop_name).iter()
+            .map(|x| x.value_as_string().trim().to_owned())
+            .find(|x| !x.is_empty())
+    }
+
+    if let Some(x) = first_value(component, "FN") {
+        return Some(x);
+    };
+
+    if let Some(n) = component.get_all("N").first() {
+        // N is family;given;additional;prefixes;suffixes
+        let parts = split_raw_value(&n.raw_value[..], ';');
+        let name_parts: Vec<&str> = [3, 1, 2, 0, 4].iter()
+            .filter_map(|&i| parts.get(i))
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .collect();
+        if !name_parts.is_empty() {
+            return Some(name_parts.join(" "));
+        };
+    };
+
+    if let Some(org) = component.get_all("ORG").first() {
+        let org_name = split_raw_value(&org.raw_value[..], ';').into_iter()
+            .map(|x| x.trim().to_owned())
+            .find(|x| !x.is_empty());
+        if org_name.is_some() {
+            return org_name;
+        };
+    };
+
+    if let Some(x) = first_value(component, "NICKNAME") {
+        return Some(x);
+    };
+
+    first_value(component, "EMAIL")
+        .and_then(|x| x.split('@').next().map(|x| x.to_owned()))
+        .and_then(|x| if x.is_empty() { None } else { Some(x) })
+}
+
+/// The values of a property as they should be shown to the user. List properties such as
+/// CATEGORIES are split into their values, the components of structured ones such as ORG are
+/// joined.
+pub fn property_values(prop: &Property) -> Vec<String> {
+    match &prop.name[..] {
+        "CATEGORIES" | "NICKNAME" => split_raw_value(&prop.raw_value[..], ',').into_iter()
+            .map(|x| x.trim().to_owned())
+            .collect(),
+        "ORG" | "N" | "ADR" => {
+            let parts: Vec<String> = split_raw_value(&prop.raw_value[..], ';').into_iter()
+                .map(|x| x.trim().to_owned())
+                .filter(|x| !x.is_empty())
+                .collect();
+            vec![parts.join("; ")]
+        },
+        _ => vec![prop.value_as_string().trim().to_owned()]
+    }
+}
+
+/// Split the raw value of a structured or list property (such as N, ADR, ORG or CATEGORIES) at
+/// every unescaped `sep`, and unescape the resulting components.
+pub fn split_raw_value(raw_value: &str, sep: char) -> Vec<String> {
+    let mut rv = vec![];
+    let mut current = String::new();
+    let mut chars = raw_value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            current.push(c);
+            if let Some(x) = chars.next() {
+                current.push(x);
+            };
+        } else if c == sep {
+            rv.push(unescape_chars(&current[..]));
+            current.clear();
+        } else {
+            current.push(c);
+        }
+    };
+    rv.push(unescape_chars(&current[..]));
+    rv
+}
+
+/// Read the items of an existing index, grouped by the contact file they belong to. Items without a
+/// file stamp are skipped, as they can't be reused. So is the whole index if its header doesn't
+/// match `expected_header`.
+fn read_index_cache(index: &IndexBackend, expected_header: &IndexHeader)
+    -> io::Result<HashMap<path::PathBuf, (FileStamp, Vec<IndexItem>)>> {
+    let mut rv: HashMap<path::PathBuf, (FileStamp, Vec<IndexItem>)> = HashMap::new();
+    let (header, items) = match index.load() {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::InvalidData => return Ok(rv),
+        Err(e) => return Err(e)
+    };
+    if header != *expected_header {
+        return Ok(rv);
+    };
+
+    for item in items {
+        if let (Some(filepath), Some(stamp)) = (item.filepath.clone(), item.stamp) {
+            rv.entry(filepath).or_insert_with(|| (stamp, vec![])).1.push(item);
+        };
     };
     Ok(rv)
 }
 
+/// Write the index for all contacts in all collections. Unless `full` is given, index lines of files
+/// whose modification time and size didn't change since the last run are reused. Contacts are
+/// parsed in parallel, but always written sorted by path.
+pub fn build_index(config: &Configuration, full: bool) -> io::Result<()> {
+    let _lock = try!(Lock::acquire(config));
+    let index = try!(open_index(config));
+    let header = IndexHeader::new(&config.index_fields[..]);
+    let collections = try!(find_collections(&config.vdir_paths[..]));
+
+    let cache = if full {
+        HashMap::new()
+    } else {
+        try!(read_index_cache(&*index, &header))
+    };
+
+    let mut errors = false;
+    let mut paths = vec![];
+
+    for collection in collections.iter() {
+        for entry in try!(fs::read_dir(&collection.path)) {
+            let entry = match entry {
+                Ok(x) => x,
+                Err(e) => {
+                    let _ = writeln!(&mut io::stderr(), "Error while listing directory: {}", e);
+                    errors = true;
+                    continue;
+                }
+            };
+
+            let pathbuf = entry.path();
+
+            if pathbuf.str_extension().unwrap_or("") != "vcf" || !pathbuf.is_file() {
+                continue;
+            };
+            paths.push((pathbuf, &collection.name[..]));
+        };
+    };
+    paths.sort();
+
+    let results: Vec<Result<Vec<IndexItem>, String>> = paths.par_iter()
+        .map(|&(ref pathbuf, collection)| {
+            let stamp = match FileStamp::from_path(pathbuf) {
+                Ok(x) => x,
+                Err(e) => return Err(format!("Error while reading {}: {}", pathbuf.display(), e))
+            };
+
+            if let Some(&(ref cached_stamp, ref items)) = cache.get(pathbuf) {
+                if *cached_stamp == stamp {
+                    // Collections may have been renamed, see `find_collections`.
+                    return Ok(items.iter()
+                              .map(|x| IndexItem { collection: collection.to_owned(), ..x.clone() })
+                              .collect());
+                };
+            };
+
+            let contact = match Contact::from_file(pathbuf) {
+                Ok(x) => x,
+                Err(e) => return Err(format!("Error while reading {}: {}", pathbuf.display(), e))
+            };
+
+            index_items_from_contact(&contact, &stamp, collection, &header.fields[..])
+                .map_err(|e| format!("Error while indexing {}: {}", pathbuf.display(), e))
+        })
+        .collect();
+
+    let mut items = vec![];
+    for result in results.into_iter() {
+        match result {
+            Ok(x) => items.extend(x.into_iter()),
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "{}", e);
+                errors = true;
+            }
+        };
+    };
+    try!(index.save(&header, &items[..]));
+
+    if errors {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Several errors happened while generating the index.",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Replace the index items of the contact at `contact_path` with freshly generated ones, or remove
+/// them if the file doesn't exist anymore.
+pub fn update_index_for_file(config: &Configuration, contact_path: &path::Path) -> io::Result<()> {
+    let lock = try!(Lock::acquire(config));
+    let index = try!(open_index(config));
+    let (old_header, items) = match index.load() {
+        Ok(x) => x,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (IndexHeader::new(&config.index_fields[..]), vec![]),
+        // E.g. written by the other backend. There is nothing to keep, so the whole index is
+        // written, which includes this contact.
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+            drop(lock);
+            return build_index(config, false);
+        },
+        Err(e) => return Err(e)
+    };
+
+    // Keep the fields of the existing index, so all of its items stay consistent. `mates index`
+    // will notice if they differ from the configured ones. Old items are converted when writing.
+    let header = match old_header.format {
+        IndexFormat::Current => old_header,
+        IndexFormat::Legacy => IndexHeader::new(&config.index_fields[..])
+    };
+
+    // Written with the path `build_index` would use, so it is sorted the same way.
+    let collection = try!(collection_of(config, contact_path));
+    let filepath = match contact_path.file_name() {
+        Some(x) => collection.path.join(x),
+        None => contact_path.to_owned()
+    };
+    let new_items = if contact_path.is_file() {
+        let stamp = try!(FileStamp::from_path(contact_path));
+        let mut contact = try!(Contact::from_file(contact_path));
+        contact.path = filepath.clone();
+        try!(index_items_from_contact(&contact, &stamp, &collection.name[..], &header.fields[..]))
+    } else {
+        vec![]
+    };
+
+    let mut normalized_dirs = HashMap::new();
+    let target = normalize_path(contact_path, &mut normalized_dirs);
+
+    let mut rv: Vec<IndexItem> = items.into_iter()
+        .filter(|item| match item.filepath {
+            Some(ref x) => normalize_path(x, &mut normalized_dirs) != target,
+            None => true
+        })
+        .collect();
+    // The index is sorted by path, see `build_index`.
+    let position = rv.iter()
+        .position(|item| match item.filepath {
+            Some(ref x) => *x > filepath,
+            None => false
+        })
+        .unwrap_or(rv.len());
+    let rest = rv.split_off(position);
+    rv.extend(new_items.into_iter());
+    rv.extend(rest.into_iter());
+    index.save(&header, &rv[..])
+}
+
+/// A difference between the index and the contact files, see `check_index`.
+pub enum IndexProblem {
+    /// The index has lines for a file that doesn't exist anymore.
+    Dangling(path::PathBuf),
+    /// A contact file has no lines in the index.
+    Unindexed(path::PathBuf),
+    /// The email addresses in the index differ from those in the contact file.
+    EmailsChanged(path::PathBuf),
+    /// The index contains this line more than once.
+    Duplicate(String),
+    /// A contact file couldn't be read, parsed or indexed. Rebuilding the index doesn't help, the
+    /// file has to be fixed.
+    Unindexable(path::PathBuf, io::Error)
+}
+
+impl IndexProblem {
+    /// Whether `mates index --full` fixes the problem.
+    pub fn fixed_by_rebuild(&self) -> bool {
+        match *self {
+            IndexProblem::Unindexable(..) => false,
+            _ => true
+        }
+    }
+}
+
+impl fmt::Display for IndexProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexProblem::Dangling(ref p) => write!(f, "Indexed file doesn't exist: {}", p.display()),
+            IndexProblem::Unindexed(ref p) => write!(f, "File is not indexed: {}", p.display()),
+            IndexProblem::EmailsChanged(ref p) => write!(f, "Email addresses changed: {}", p.display()),
+            IndexProblem::Duplicate(ref line) => write!(f, "Duplicate index line: {}", line),
+            IndexProblem::Unindexable(ref p, ref e) => write!(f, "Can't index {}: {}", p.display(), e)
+        }
+    }
+}
+
+/// Compare the index with the contact files without changing anything. Unlike `index_is_stale`,
+/// this parses every contact file.
+pub fn check_index(config: &Configuration) -> io::Result<Vec<IndexProblem>> {
+    let (_, items) = try!(try!(open_index(config)).load());
+    let mut normalized_dirs = HashMap::new();
+    let mut problems = vec![];
+
+    // Email addresses per indexed file, keyed by the normalized path.
+    let mut indexed: BTreeMap<path::PathBuf, (path::PathBuf, BTreeSet<String>)> = BTreeMap::new();
+    let mut seen_lines = HashSet::new();
+    let mut duplicates = BTreeSet::new();
+
+    for item in items {
+        let line = item.to_line().trim_right_matches('\n').to_owned();
+        if let Some(filepath) = item.filepath {
+            let key = normalize_path(&filepath, &mut normalized_dirs);
+            let entry = indexed.entry(key).or_insert_with(|| (filepath, BTreeSet::new()));
+            if !item.email.is_empty() {
+                entry.1.insert(item.email);
+            };
+        };
+        if !seen_lines.insert(line.clone()) {
+            duplicates.insert(line);
+        };
+    };
+
+    for collection in try!(find_collections(&config.vdir_paths[..])) {
+        let mut paths = vec![];
+        for entry in try!(fs::read_dir(&collection.path)) {
+            let pathbuf = try!(entry).path();
+            if pathbuf.str_extension().unwrap_or("") == "vcf" && pathbuf.is_file() {
+                paths.push(pathbuf);
+            };
+        };
+        paths.sort();
+
+        for pathbuf in paths {
+            let indexed_emails = match indexed.remove(&normalize_path(&pathbuf, &mut normalized_dirs)) {
+                Some((_, x)) => x,
+                None => {
+                    // Only reported as missing if `mates index` would add it.
+                    let indexed = FileStamp::from_path(&pathbuf)
+                        .and_then(|stamp| Contact::from_file(&pathbuf).map(|contact| (stamp, contact)))
+                        .and_then(|(stamp, contact)| {
+                            index_items_from_contact(&contact, &stamp, &collection.name[..], &config.index_fields[..])
+                        });
+                    problems.push(match indexed {
+                        Ok(_) => IndexProblem::Unindexed(pathbuf),
+                        Err(e) => IndexProblem::Unindexable(pathbuf, e)
+                    });
+                    continue;
+                }
+            };
+
+            let contact = match Contact::from_file(&pathbuf) {
+                Ok(x) => x,
+                Err(e) => {
+                    problems.push(IndexProblem::Unindexable(pathbuf, e));
+                    continue;
+                }
+            };
+            let emails: BTreeSet<String> = contact.component.get_all("EMAIL").iter()
+                .map(|x| x.value_as_string())
+                .filter(|x| !x.is_empty())
+                .collect();
+            if emails != indexed_emails {
+                problems.push(IndexProblem::EmailsChanged(pathbuf));
+            };
+        };
+    };
+
+    for (_, (filepath, _)) in indexed.into_iter() {
+        problems.push(IndexProblem::Dangling(filepath));
+    };
+    for line in duplicates.into_iter() {
+        problems.push(IndexProblem::Duplicate(line));
+    };
+    Ok(problems)
+}
+
+/// Make a path comparable to other paths pointing to the same file, even if that file doesn't
+/// exist anymore. Canonicalized directories are cached in `dirs`.
+fn normalize_path(p: &path::Path, dirs: &mut HashMap<path::PathBuf, path::PathBuf>) -> path::PathBuf {
+    let (parent, filename) = match (p.parent(), p.file_name()) {
+        (Some(parent), Some(filename)) => (parent, filename),
+        _ => return p.to_owned()
+    };
+    let parent = if parent.as_os_str().is_empty() { path::Path::new(".") } else { parent };
+
+    let normalized_parent = dirs.entry(parent.to_owned())
+        .or_insert_with(|| fs::canonicalize(parent).unwrap_or_else(|_| parent.to_owned()));
+    normalized_parent.join(filename)
+}
+
 /// Return a tuple (fullname, email)
 pub fn parse_from_header<'a>(s: &'a String) -> (Option<&'a str>, Option<&'a str>) {
     let mut split = s.rsplitn(2, '<');
@@ -262,11 +1427,206 @@ pub fn add_contact_from_email(contact_dir: &path::Path, email_input: &str) -> io
 }
 
 
-fn command_from_config(config_val: &str) -> process::Command {
-    let mut parts = config_val.split(' ');
-    let main = parts.next().unwrap();
-    let rest: Vec<_> = parts.map(|x| x.to_string()).collect();
+fn command_from_config(config_val: &str) -> io::Result<process::Command> {
+    let parts = try!(split_command_line(config_val));
+    let (main, rest) = match parts.split_first() {
+        Some(x) => x,
+        None => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Empty command.",
+        ))
+    };
     let mut rv = process::Command::new(main);
-    rv.args(&rest[..]);
-    rv
+    rv.args(rest);
+    Ok(rv)
+}
+
+/// Split a command line into arguments like a POSIX shell would, honoring single quotes, double
+/// quotes and backslash escapes. No other shell features are supported.
+fn split_command_line(s: &str) -> io::Result<Vec<String>> {
+    let mut rv = vec![];
+    let mut current: Option<String> = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if let Some(x) = current.take() {
+                    rv.push(x);
+                };
+            },
+            '\'' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(x) => arg.push(x),
+                        None => return Err(unterminated_quote_error())
+                    };
+                };
+            },
+            '"' => {
+                let arg = current.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(x @ '"') | Some(x @ '\\') => arg.push(x),
+                            Some(x) => { arg.push('\\'); arg.push(x); },
+                            None => return Err(unterminated_quote_error())
+                        },
+                        Some(x) => arg.push(x),
+                        None => return Err(unterminated_quote_error())
+                    };
+                };
+            },
+            '\\' => {
+                let arg = current.get_or_insert_with(String::new);
+                if let Some(x) = chars.next() {
+                    arg.push(x);
+                };
+            },
+            x => current.get_or_insert_with(String::new).push(x)
+        };
+    };
+
+    if let Some(x) = current.take() {
+        rv.push(x);
+    };
+    Ok(rv)
+}
+
+fn unterminated_quote_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Unterminated quote in command.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(s: &str) -> Vec<String> {
+        split_command_line(s).unwrap()
+    }
+
+    #[test]
+    fn split_command_line_whitespace() {
+        assert_eq!(split("grep -i"), vec!["grep", "-i"]);
+        assert_eq!(split("  grep \t -i\n"), vec!["grep", "-i"]);
+        assert!(split("").is_empty());
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn split_command_line_quotes() {
+        assert_eq!(split("grep 'a b' \"c d\""), vec!["grep", "a b", "c d"]);
+        assert_eq!(split("a'b c'd"), vec!["ab cd"]);
+        assert_eq!(split("'' \"\""), vec!["", ""]);
+        assert_eq!(split("'a\"b'"), vec!["a\"b"]);
+        assert_eq!(split("\"a'b\""), vec!["a'b"]);
+    }
+
+    #[test]
+    fn split_command_line_backslashes() {
+        assert_eq!(split("a\\ b"), vec!["a b"]);
+        assert_eq!(split("\"a\\\"b\\\\c\""), vec!["a\"b\\c"]);
+        // Inside double quotes, other backslashes are kept.
+        assert_eq!(split("\"a\\nb\""), vec!["a\\nb"]);
+        // Inside single quotes, nothing is special.
+        assert_eq!(split("'a\\b'"), vec!["a\\b"]);
+    }
+
+    #[test]
+    fn split_command_line_unterminated() {
+        assert!(split_command_line("grep 'a").is_err());
+        assert!(split_command_line("grep \"a").is_err());
+        assert!(split_command_line("grep \"a\\").is_err());
+    }
+
+    #[test]
+    fn escape_index_field_round_trip() {
+        for s in &["", "plain", "tab\there", "new\nline\r\n", "back\\slash\\t", "trailing\\", "a,b"] {
+            let escaped = escape_index_field(s);
+            assert!(!escaped.contains('\t') && !escaped.contains('\n') && !escaped.contains('\r'));
+            assert_eq!(unescape_index_field(&escaped[..]), *s);
+        };
+    }
+
+    #[test]
+    fn unescape_index_field_unknown_escapes() {
+        assert_eq!(unescape_index_field("a\\xb"), "a\\xb");
+        assert_eq!(unescape_index_field("a\\"), "a\\");
+    }
+
+    #[test]
+    fn index_values_round_trip() {
+        let cases: Vec<Vec<String>> = vec![
+            vec![],
+            vec!["one".to_owned()],
+            vec!["a,b".to_owned(), "c\\,d".to_owned(), "tab\t".to_owned(), "\\".to_owned()],
+            vec!["x".to_owned(), "".to_owned(), "y".to_owned()]
+        ];
+        for values in cases {
+            let joined = join_index_values(&values[..]);
+            assert!(!joined.contains('\t'));
+            assert_eq!(split_index_values(&joined[..]), values);
+        };
+    }
+
+    #[test]
+    fn index_item_round_trip() {
+        let header = IndexHeader::new(&["ORG".to_owned(), "NOTE".to_owned()]);
+        let item = IndexItem {
+            email: "tab\t@example.com".to_owned(),
+            name: "Name with\nnewline and \\ backslash".to_owned(),
+            filepath: Some(path::PathBuf::from("/contacts/with\ttab.vcf")),
+            stamp: Some(FileStamp { mtime_secs: 1500000000, mtime_nanos: 42, size: 123 }),
+            collection: "work".to_owned(),
+            uid: "uid\r".to_owned(),
+            tels: vec!["+4930123".to_owned(), "0800".to_owned()],
+            fields: vec![
+                ("ORG".to_owned(), vec!["ACME, Inc.".to_owned()]),
+                ("NOTE".to_owned(), vec![])
+            ]
+        };
+
+        let line = item.to_line();
+        assert_eq!(line.matches('\n').count(), 1);
+        let parsed = IndexItem::new(line.trim_right_matches('\n'), &header);
+        assert_eq!(parsed.email, item.email);
+        assert_eq!(parsed.name, item.name);
+        assert_eq!(parsed.filepath, item.filepath);
+        assert_eq!(parsed.stamp, item.stamp);
+        assert_eq!(parsed.collection, item.collection);
+        assert_eq!(parsed.uid, item.uid);
+        assert_eq!(parsed.tels, item.tels);
+        assert_eq!(parsed.fields, item.fields);
+    }
+
+    fn contact(path: &str, text: &str) -> Contact {
+        Contact { component: parse_component(text).unwrap(), path: path::PathBuf::from(path) }
+    }
+
+    #[test]
+    fn index_contacts_without_name() {
+        let stamp = FileStamp { mtime_secs: 0, mtime_nanos: 0, size: 0 };
+        let tel_only = contact("/c/tel.vcf", "BEGIN:VCARD\r\nVERSION:3.0\r\nTEL:+49 30 123\r\nEND:VCARD\r\n");
+        let items = index_items_from_contact(&tel_only, &stamp, "c", &[]).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "+49 30 123");
+        assert_eq!(items[0].email, "");
+        assert_eq!(items[0].tels, vec!["+4930123".to_owned()]);
+
+        let empty = contact("/c/nothing.vcf", "BEGIN:VCARD\r\nVERSION:3.0\r\nEND:VCARD\r\n");
+        let items = index_items_from_contact(&empty, &stamp, "c", &[]).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].name, "nothing");
+    }
+
+    #[test]
+    fn index_header_round_trip() {
+        let header = IndexHeader::new(&["ORG".to_owned(), "NICKNAME".to_owned()]);
+        assert_eq!(IndexHeader::parse(header.to_line().trim_right()).unwrap(), header);
+        assert_eq!(IndexHeader::parse("a@b.c\tName\t/path.vcf").unwrap().format, IndexFormat::Legacy);
+        assert!(IndexHeader::parse("# mates index v1").is_err());
+    }
 }
@@ -0,0 +1,157 @@
+//! Keeping the index up to date by watching the collections for changes, see `mates watch`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::io;
+use std::path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{DebouncedEvent,RecursiveMode,Watcher,watcher};
+
+use cli::Configuration;
+use utils;
+use utils::CustomPathExt;
+
+/// How long a file has to stay unchanged before its index lines are updated. vdirsyncer and
+/// editors write files in several steps, usually ending with a rename.
+const DEBOUNCE_DELAY_MS: u64 = 500;
+
+/// Events arriving less than this apart are handled together.
+const BATCH_DELAY_MS: u64 = 200;
+
+/// If a batch of events touches more files than this, the index is updated with `build_index`,
+/// which only rewrites it once, instead of once per file.
+const MAX_SINGLE_UPDATES: usize = 20;
+
+/// What has to be done after a batch of events.
+struct Changes {
+    paths: BTreeSet<path::PathBuf>,
+    rescan: bool
+}
+
+impl Changes {
+    fn new() -> Changes {
+        Changes { paths: BTreeSet::new(), rescan: false }
+    }
+
+    fn add(&mut self, event: DebouncedEvent) {
+        match event {
+            DebouncedEvent::Create(p) |
+            DebouncedEvent::Write(p) |
+            DebouncedEvent::Chmod(p) |
+            DebouncedEvent::Remove(p) => self.add_path(p),
+            DebouncedEvent::Rename(from, to) => {
+                self.add_path(from);
+                self.add_path(to);
+            },
+            DebouncedEvent::Rescan => self.rescan = true,
+            DebouncedEvent::Error(e, p) => {
+                let _ = match p {
+                    Some(p) => writeln!(&mut io::stderr(), "Error while watching {}: {}", p.display(), e),
+                    None => writeln!(&mut io::stderr(), "Error while watching: {}", e)
+                };
+                self.rescan = true;
+            },
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => ()
+        }
+    }
+
+    fn add_path(&mut self, p: path::PathBuf) {
+        // Temporary files, like those of atomic writes (including our own writes of the index),
+        // are hidden.
+        let hidden = match p.file_name().and_then(|x| x.to_str()) {
+            Some(x) => x.starts_with('.'),
+            None => true
+        };
+        if hidden {
+            return;
+        };
+
+        if p.str_extension().unwrap_or("") == "vcf" {
+            self.paths.insert(p);
+        } else if p.is_dir() || (!p.exists() && p.extension().is_none()) {
+            // A collection might have been created, removed or renamed.
+            self.rescan = true;
+        }
+    }
+}
+
+/// Watch all directories in `MATES_DIR` and update the index whenever contact files are created,
+/// changed, removed or renamed. Only returns if watching fails.
+pub fn watch(config: &Configuration) -> io::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = try!(watcher(tx, Duration::from_millis(DEBOUNCE_DELAY_MS)).map_err(watch_error));
+    for dir in config.vdir_paths.iter() {
+        try!(watcher.watch(dir, RecursiveMode::Recursive).map_err(watch_error));
+    };
+
+    // Pick up whatever changed while nobody was watching.
+    update_all(config);
+
+    loop {
+        let mut changes = Changes::new();
+        match rx.recv() {
+            Ok(event) => changes.add(event),
+            Err(_) => return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Stopped receiving events from the file watcher."
+            ))
+        };
+        // Handle a burst of events, like a vdirsyncer run, at once.
+        while let Ok(event) = rx.recv_timeout(Duration::from_millis(BATCH_DELAY_MS)) {
+            changes.add(event);
+        };
+
+        if changes.rescan || changes.paths.len() > MAX_SINGLE_UPDATES {
+            update_all(config);
+            continue;
+        };
+
+        // Paths of events are absolute, `MATES_DIR` might not be.
+        let collection_paths: Vec<path::PathBuf> = match utils::find_collections(&config.vdir_paths[..]) {
+            Ok(x) => x.into_iter().map(|x| canonicalize(&x.path)).collect(),
+            Err(e) => {
+                let _ = writeln!(&mut io::stderr(), "Error while listing collections: {}", e);
+                continue;
+            }
+        };
+
+        for p in changes.paths.iter() {
+            // Ignore files in hidden directories and other places `mates index` doesn't look at.
+            let in_collection = match p.parent() {
+                Some(parent) => collection_paths.contains(&canonicalize(parent)),
+                None => false
+            };
+            if !in_collection {
+                continue;
+            };
+
+            match utils::update_index_for_file(config, p) {
+                Ok(()) => println!("Updated {}", p.display()),
+                Err(e) => {
+                    let _ = writeln!(&mut io::stderr(), "Error while indexing {}: {}", p.display(), e);
+                }
+            };
+        };
+    }
+}
+
+/// Update the index for all contacts. Errors are only reported, as the next change might fix them.
+fn update_all(config: &Configuration) {
+    match utils::build_index(config, false) {
+        Ok(()) => println!("Updated index file \"{}\"", config.index_path.display()),
+        Err(e) => {
+            let _ = writeln!(&mut io::stderr(), "Error while updating the index: {}", e);
+        }
+    };
+}
+
+fn canonicalize(p: &path::Path) -> path::PathBuf {
+    fs::canonicalize(p).unwrap_or_else(|_| p.to_owned())
+}
+
+fn watch_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("Failed to watch for changes: {}", e.to_string()))
+}